@@ -0,0 +1,300 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use m8_files::{Instrument, Song, FX};
+use midi_msg::{ChannelVoiceMsg, ControlChange};
+
+/// What Midi message an M8 FX command is turned into
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FxMapping {
+    /// A control change for the given controller (0-119). The FX value (00-FF) is scaled
+    /// to 0-127.
+    ControlChange(u8),
+    /// A control change for the controller configured in the given CC slot of a MIDI OUT
+    /// (0-7 for CCA-CCH) or EXTERNAL (0-3 for CCA-CCD) instrument. The FX value is used as-is,
+    /// up to 127.
+    InstrumentControlChange(usize),
+    /// Pitch bend. The FX value is signed (80-FF bend down), with 00 being no bend.
+    PitchBend,
+    /// Channel pressure (aftertouch). The FX value (00-FF) is scaled to 0-127.
+    ChannelPressure,
+}
+
+impl FxMapping {
+    /// The Midi message for an FX value, played by the given instrument
+    pub fn to_msg(&self, value: u8, instrument: Option<&Instrument>) -> Option<ChannelVoiceMsg> {
+        match *self {
+            Self::ControlChange(control) => Some(ChannelVoiceMsg::ControlChange {
+                control: ControlChange::Undefined {
+                    control,
+                    value: value >> 1,
+                },
+            }),
+            Self::InstrumentControlChange(slot) => {
+                let cc = match instrument {
+                    Some(Instrument::MIDIOut(i)) => i.custom_cc.get(slot),
+                    Some(Instrument::External(i)) => {
+                        [&i.cca, &i.ccb, &i.ccc, &i.ccd].get(slot).copied()
+                    }
+                    _ => None,
+                }?;
+                // Controllers from 120 are channel mode messages
+                (cc.number < 120).then_some(ChannelVoiceMsg::ControlChange {
+                    control: ControlChange::Undefined {
+                        control: cc.number,
+                        value: value.min(0x7F),
+                    },
+                })
+            }
+            Self::PitchBend => Some(ChannelVoiceMsg::PitchBend {
+                bend: (0x2000 + (value as i8) as i16 * 64) as u16,
+            }),
+            Self::ChannelPressure => Some(ChannelVoiceMsg::ChannelPressure {
+                pressure: value >> 1,
+            }),
+        }
+    }
+}
+
+impl FromStr for FxMapping {
+    type Err = String;
+
+    /// Parses `cc<N>`, `pb` (pitch bend) or `at` (aftertouch)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().to_lowercase();
+        match s.as_str() {
+            "pb" => Ok(Self::PitchBend),
+            "at" => Ok(Self::ChannelPressure),
+            _ => s
+                .strip_prefix("cc")
+                .and_then(|n| n.parse::<u8>().ok())
+                .filter(|n| *n < 120)
+                .map(Self::ControlChange)
+                .ok_or_else(|| format!("Invalid FX mapping `{}`. Expected cc<0-119>, pb or at", s)),
+        }
+    }
+}
+
+/// The first instrument FX command. Commands from here on depend on the kind of instrument.
+const INSTRUMENT_COMMANDS_START: u8 = 0x80;
+
+/// Names of the FX commands of each kind of instrument, starting at `INSTRUMENT_COMMANDS_START`.
+/// Only the commands before those of the instrument's modulators are named.
+fn instrument_commands(instrument: &Instrument) -> &'static [&'static str] {
+    match instrument {
+        Instrument::WavSynth(_) => &[
+            "VOL", "PIT", "FIN", "OSC", "SIZ", "MUL", "WRP", "MIR", "FIL", "CUT", "RES", "AMP",
+            "LIM", "PAN", "DRY", "SCH", "SDL", "SRV",
+        ],
+        Instrument::MacroSynth(_) => &[
+            "VOL", "PIT", "FIN", "OSC", "TBR", "COL", "DEG", "RED", "FIL", "CUT", "RES", "AMP",
+            "LIM", "PAN", "DRY", "SCH", "SDL", "SRV",
+        ],
+        Instrument::Sampler(_) => &[
+            "VOL", "PIT", "FIN", "PLY", "STA", "LOP", "LEN", "DEG", "FLT", "CUT", "RES", "AMP",
+            "LIM", "PAN", "DRY", "SCH", "SDL", "SRV",
+        ],
+        Instrument::FMSynth(_) => &[
+            "VOL", "PIT", "FIN", "ALG", "FM1", "FM2", "FM3", "FM4", "FLT", "CUT", "RES", "AMP",
+            "LIM", "PAN", "DRY", "SCH", "SDL", "SRV",
+        ],
+        Instrument::HyperSynth(_) => &[
+            "VOL", "PIT", "FIN", "CRD", "SHF", "SWM", "WID", "SUB", "FLT", "CUT", "RES", "AMP",
+            "LIM", "PAN", "DRY", "SCH", "SDL", "SRV",
+        ],
+        Instrument::MIDIOut(_) => &[
+            "VOL", "PIT", "MPG", "MPB", "ADD", "CHD", "CCA", "CCB", "CCC", "CCD", "CCE", "CCF",
+            "CCG", "CCH", "CCI", "CCJ",
+        ],
+        Instrument::External(_) => &[
+            "VOL", "PIT", "MPB", "MPG", "CCA", "CCB", "CCC", "CCD", "FLT", "CUT", "RES", "AMP",
+            "LIM", "PAN", "DRY", "SCH", "SDL", "SRV",
+        ],
+        Instrument::None => &[],
+    }
+}
+
+/// The name of an instrument FX command, as played by the given instrument
+pub fn instrument_command_name(fx: &FX, instrument: Option<&Instrument>) -> Option<&'static str> {
+    let i = fx.command.checked_sub(INSTRUMENT_COMMANDS_START)? as usize;
+    instrument_commands(instrument?).get(i).copied()
+}
+
+/// Which M8 FX commands are turned into Midi CC, pitch bend and aftertouch events
+#[derive(Debug, Clone, PartialEq)]
+pub struct FxMap {
+    /// M8 command names and what they map to
+    pub mappings: BTreeMap<String, FxMapping>,
+}
+
+impl FxMap {
+    /// A map that doesn't output anything
+    pub fn empty() -> Self {
+        Self {
+            mappings: BTreeMap::new(),
+        }
+    }
+
+    /// Map `command` to `mapping`, or remove its mapping if `None`
+    pub fn set(&mut self, command: &str, mapping: Option<FxMapping>) {
+        let command = command.trim().to_uppercase();
+        match mapping {
+            Some(m) => {
+                self.mappings.insert(command, m);
+            }
+            None => {
+                self.mappings.remove(&command);
+            }
+        }
+    }
+
+    /// Apply a comma separated list of overrides, e.g. `VOL=cc7,PIT=pb,CUT=off`
+    pub fn apply_overrides(&mut self, overrides: &str) -> Result<(), String> {
+        for o in overrides.split(',').filter(|o| !o.trim().is_empty()) {
+            let (command, mapping) = o
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid FX map override `{}`. Expected CMD=MAPPING", o))?;
+            if mapping.trim().eq_ignore_ascii_case("off") {
+                self.set(command, None);
+            } else {
+                self.set(command, Some(mapping.parse()?));
+            }
+        }
+        Ok(())
+    }
+
+    /// The mapping of an FX command played by the given instrument, if any
+    pub fn get(&self, fx: &FX, song: &Song, instrument: Option<&Instrument>) -> Option<FxMapping> {
+        // Skip empty commands, for the same reason as `FxCommand::from_fx`
+        if fx.command == 0xFF {
+            return None;
+        }
+        let instrument_command = instrument_command_name(fx, instrument);
+        self.mappings
+            .iter()
+            .find(|(command, _)| {
+                instrument_command == Some(command.as_str()) || fx.command_eq(command, song.version)
+            })
+            .map(|(_, mapping)| *mapping)
+    }
+}
+
+impl Default for FxMap {
+    fn default() -> Self {
+        let mut map = Self::empty();
+        map.set("VOL", Some(FxMapping::ControlChange(7)));
+        map.set("PAN", Some(FxMapping::ControlChange(10)));
+        map.set("CUT", Some(FxMapping::ControlChange(74)));
+        map.set("RES", Some(FxMapping::ControlChange(71)));
+        map.set("PIT", Some(FxMapping::PitchBend));
+        // The settings of MIDI OUT's CCI and CCJ aren't read from song files, so they are
+        // left unmapped
+        for (slot, command) in ["CCA", "CCB", "CCC", "CCD", "CCE", "CCF", "CCG", "CCH"]
+            .iter()
+            .enumerate()
+        {
+            map.set(command, Some(FxMapping::InstrumentControlChange(slot)));
+        }
+        map
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fx_map::*;
+    use m8_files::{AHDEnv, MIDIOut, Mod};
+
+    #[test]
+    fn test_apply_overrides() {
+        let mut map = FxMap::default();
+        map.apply_overrides("vol=cc11, PIT=at,CUT=off").unwrap();
+        assert_eq!(map.mappings.get("VOL"), Some(&FxMapping::ControlChange(11)));
+        assert_eq!(map.mappings.get("PIT"), Some(&FxMapping::ChannelPressure));
+        assert_eq!(map.mappings.get("CUT"), None);
+        assert!(map.apply_overrides("VOL=cc120").is_err());
+        assert!(map.apply_overrides("VOL").is_err());
+    }
+
+    #[test]
+    fn test_to_msg() {
+        assert_eq!(
+            FxMapping::ControlChange(74).to_msg(0xFF, None),
+            Some(ChannelVoiceMsg::ControlChange {
+                control: ControlChange::Undefined {
+                    control: 74,
+                    value: 0x7F
+                }
+            })
+        );
+        assert_eq!(
+            FxMapping::PitchBend.to_msg(0x00, None),
+            Some(ChannelVoiceMsg::PitchBend { bend: 0x2000 })
+        );
+        assert_eq!(
+            FxMapping::PitchBend.to_msg(0x80, None),
+            Some(ChannelVoiceMsg::PitchBend { bend: 0 })
+        );
+        assert_eq!(
+            FxMapping::InstrumentControlChange(0).to_msg(0x10, None),
+            None
+        );
+    }
+
+    #[test]
+    fn test_instrument_commands() {
+        let midi_out = Instrument::MIDIOut(MIDIOut {
+            number: 0,
+            name: String::new(),
+            transpose: true,
+            table_tick: 1,
+            port: 0,
+            channel: 0,
+            bank_select: 0xFF,
+            program_change: 0xFF,
+            custom_cc: std::array::from_fn(|i| m8_files::ControlChange {
+                number: 20 + i as u8,
+                value: 0,
+            }),
+            mods: std::array::from_fn(|_| Mod::AHDEnv(AHDEnv::default())),
+        });
+        let cca = FX {
+            command: 0x86,
+            value: 0x10,
+        };
+        assert_eq!(instrument_command_name(&cca, Some(&midi_out)), Some("CCA"));
+        assert_eq!(instrument_command_name(&cca, Some(&Instrument::None)), None);
+        assert_eq!(instrument_command_name(&cca, None), None);
+        let tpo = FX {
+            command: 0x15,
+            value: 0,
+        };
+        assert_eq!(instrument_command_name(&tpo, Some(&midi_out)), None);
+
+        let song = Song::read(&mut &include_bytes!("../test_songs/DEFAULT.m8s")[..]).unwrap();
+        let map = FxMap::default();
+        assert_eq!(
+            map.get(&cca, &song, Some(&midi_out)),
+            Some(FxMapping::InstrumentControlChange(0))
+        );
+        let empty = FX {
+            command: 0xFF,
+            value: 0,
+        };
+        assert_eq!(map.get(&empty, &song, Some(&midi_out)), None);
+        let cci = FX {
+            command: 0x8E,
+            value: 0x10,
+        };
+        assert_eq!(instrument_command_name(&cci, Some(&midi_out)), Some("CCI"));
+        assert_eq!(map.get(&cci, &song, Some(&midi_out)), None);
+        assert_eq!(
+            FxMapping::InstrumentControlChange(0).to_msg(0x10, Some(&midi_out)),
+            Some(ChannelVoiceMsg::ControlChange {
+                control: ControlChange::Undefined {
+                    control: 20,
+                    value: 0x10
+                }
+            })
+        );
+    }
+}
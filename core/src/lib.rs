@@ -1,2 +1,6 @@
+pub mod fx_map;
 pub mod midi_file;
+pub mod report;
+pub mod row_range;
 pub mod song_to_midi;
+pub mod track_set;
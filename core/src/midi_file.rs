@@ -1,6 +1,140 @@
+use std::fmt;
+use std::io::{self, Write};
+
 use byteorder::{BigEndian, ByteOrder};
 use midi_msg::*;
 
+/// Errors encountered while reading or writing a Midi file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The data ended in the middle of a chunk or event
+    UnexpectedEof,
+    /// The data doesn't start with a `MThd` header chunk
+    MissingHeader,
+    /// The header has an unknown format
+    InvalidFormat(u16),
+    /// The header uses SMPTE (subdivision-of-second) time division, which isn't supported
+    UnsupportedTimeDivision(u16),
+    /// A variable length quantity is longer than four bytes
+    InvalidVariableLength,
+    /// A data byte was found where a status byte was expected, with no running status to use
+    MissingStatus { track: usize, offset: usize },
+    /// A status byte that can't appear in a Midi file
+    InvalidStatus {
+        track: usize,
+        offset: usize,
+        status: u8,
+    },
+    /// A number too large to be written as a variable length quantity
+    VariableLengthTooLarge(u32),
+    /// Ticks per quarter note must be at most 0x7FFF
+    InvalidTicksPerQuarterNote(u16),
+    /// A Midi file can hold at most 0xFFFF tracks
+    TooManyTracks(usize),
+    /// The requested track doesn't exist
+    TrackNotFound(usize),
+    /// A format 0 file must hold exactly one track
+    SingleTrackFormat(usize),
+    /// Writing the Midi data failed
+    Io {
+        kind: io::ErrorKind,
+        message: String,
+    },
+    /// An event comes before the event preceding it
+    UnsortedEvents {
+        track: usize,
+        tick: u32,
+        previous_tick: u32,
+    },
+    /// An event comes after the end of its track
+    EventAfterEnd {
+        track: usize,
+        tick: u32,
+        n_ticks: u32,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::UnexpectedEof => write!(f, "Unexpected end of Midi data"),
+            Self::MissingHeader => write!(f, "Not a Midi file: missing MThd header"),
+            Self::InvalidFormat(format) => write!(f, "Invalid Midi file format {}", format),
+            Self::UnsupportedTimeDivision(division) => write!(
+                f,
+                "Unsupported SMPTE time division {:#06X}. Only ticks per quarter note are supported",
+                division
+            ),
+            Self::InvalidVariableLength => {
+                write!(f, "Variable length quantity is longer than four bytes")
+            }
+            Self::MissingStatus { track, offset } => write!(
+                f,
+                "Missing status byte in track {} at offset {:#X}",
+                track, offset
+            ),
+            Self::InvalidStatus {
+                track,
+                offset,
+                status,
+            } => write!(
+                f,
+                "Invalid status byte {:#04X} in track {} at offset {:#X}",
+                status, track, offset
+            ),
+            Self::VariableLengthTooLarge(x) => write!(
+                f,
+                "{:#X} is too large to be written as a variable length quantity",
+                x
+            ),
+            Self::InvalidTicksPerQuarterNote(ticks) => write!(
+                f,
+                "Ticks per quarter note must be at most {}. Got {}",
+                0x7FFF, ticks
+            ),
+            Self::TooManyTracks(n) => {
+                write!(f, "A Midi file can hold at most {} tracks. Got {}", 0xFFFF, n)
+            }
+            Self::TrackNotFound(track) => write!(f, "There is no track {}", track),
+            Self::Io { message, .. } => write!(f, "Failed to write Midi data: {}", message),
+            Self::SingleTrackFormat(n) => write!(
+                f,
+                "A format 0 Midi file must hold exactly one track. Got {}",
+                n
+            ),
+            Self::UnsortedEvents {
+                track,
+                tick,
+                previous_tick,
+            } => write!(
+                f,
+                "Event at tick {} in track {} comes after an event at tick {}. Events must be sorted",
+                tick, track, previous_tick
+            ),
+            Self::EventAfterEnd {
+                track,
+                tick,
+                n_ticks,
+            } => write!(
+                f,
+                "Event at tick {} in track {} comes after the end of the track at tick {}",
+                tick, track, n_ticks
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Self::Io {
+            kind: e.kind(),
+            message: e.to_string(),
+        }
+    }
+}
+
 // Midi file handling
 #[inline]
 pub fn u32_to_bytes(x: u32) -> [u8; 4] {
@@ -32,7 +166,7 @@ pub fn push_u16(x: u16, v: &mut Vec<u8>) {
     v.push(b2);
 }
 
-pub fn push_vari(x: u32, v: &mut Vec<u8>) {
+pub fn push_vari(x: u32, v: &mut Vec<u8>) -> Result<(), Error> {
     if x < 0x00000080 {
         v.push(x as u8 & 0b01111111);
     } else if x < 0x00004000 {
@@ -48,11 +182,169 @@ pub fn push_vari(x: u32, v: &mut Vec<u8>) {
         v.push(((x >> 7) as u8 & 0b01111111) + 0b10000000);
         v.push(x as u8 & 0b01111111);
     } else {
-        panic!("Cannot use such a large number as a variable quantity")
+        return Err(Error::VariableLengthTooLarge(x));
+    }
+    Ok(())
+}
+
+/// Read a variable length quantity from the start of `bytes`.
+/// Returns the value and the number of bytes read.
+pub fn read_vari(bytes: &[u8]) -> Result<(u32, usize), Error> {
+    let mut x: u32 = 0;
+    for (i, b) in bytes.iter().enumerate().take(4) {
+        x = (x << 7) | (b & 0b01111111) as u32;
+        if b & 0b10000000 == 0 {
+            return Ok((x, i + 1));
+        }
+    }
+    if bytes.len() < 4 {
+        Err(Error::UnexpectedEof)
+    } else {
+        Err(Error::InvalidVariableLength)
+    }
+}
+
+/// Meta events that can be written to a track
+#[derive(Debug, Clone, PartialEq)]
+pub enum MetaEvent {
+    /// Set Tempo, in microseconds per quarter note
+    Tempo(u32),
+    /// Time Signature. The denominator must be a power of two.
+    TimeSignature { numerator: u8, denominator: u8 },
+    /// Marker, naming a point in the sequence, such as the start of a section
+    Marker(String),
+    /// Cue Point, describing something that happens at a point in the sequence
+    CuePoint(String),
+    /// Text event
+    Text(String),
+    /// Any other meta event, by type and data
+    Other { meta_type: u8, data: Vec<u8> },
+}
+
+impl MetaEvent {
+    /// A Set Tempo event for the given beats per minute
+    pub fn tempo_from_bpm(bpm: f32) -> Self {
+        let micros = (60_000_000.0 / bpm.max(1.0)) as u32;
+        Self::Tempo(micros.min(0xFFFFFF))
+    }
+
+    pub fn extend_midi(&self, v: &mut Vec<u8>) -> Result<(), Error> {
+        match self {
+            Self::Tempo(micros) => {
+                let [_, b1, b2, b3] = u32_to_bytes(*micros);
+                v.extend_from_slice(&[0xFF, 0x51, 0x03, b1, b2, b3]);
+                Ok(())
+            }
+            Self::TimeSignature {
+                numerator,
+                denominator,
+            } => {
+                // Denominator is stored as a power of two, followed by the Midi clocks
+                // per metronome click (one per beat) and 32nd notes per quarter note
+                let clocks = (96 / (*denominator).max(1) as u32).max(1) as u8;
+                v.extend_from_slice(&[
+                    0xFF,
+                    0x58,
+                    0x04,
+                    *numerator,
+                    denominator.trailing_zeros() as u8,
+                    clocks,
+                    8,
+                ]);
+                Ok(())
+            }
+            Self::Marker(text) => extend_meta(0x06, text.as_bytes(), v),
+            Self::CuePoint(text) => extend_meta(0x07, text.as_bytes(), v),
+            Self::Text(text) => extend_meta(0x01, text.as_bytes(), v),
+            Self::Other { meta_type, data } => extend_meta(*meta_type, data, v),
+        }
+    }
+
+    /// The meta event of the given type and data
+    fn from_data(meta_type: u8, data: &[u8]) -> Self {
+        let text = || String::from_utf8_lossy(data).into_owned();
+        match (meta_type, data) {
+            (0x01, _) => Self::Text(text()),
+            (0x06, _) => Self::Marker(text()),
+            (0x07, _) => Self::CuePoint(text()),
+            (0x51, [b1, b2, b3]) => Self::Tempo(u32::from_be_bytes([0, *b1, *b2, *b3])),
+            (0x58, [numerator, denominator, _, _]) if *denominator < 8 => Self::TimeSignature {
+                numerator: *numerator,
+                denominator: 1 << denominator,
+            },
+            _ => Self::Other {
+                meta_type,
+                data: data.to_vec(),
+            },
+        }
+    }
+}
+
+/// Write a meta event of the given type
+fn extend_meta(meta_type: u8, data: &[u8], v: &mut Vec<u8>) -> Result<(), Error> {
+    v.extend_from_slice(&[0xFF, meta_type]);
+    push_vari(data.len() as u32, v)?;
+    v.extend_from_slice(data);
+    Ok(())
+}
+
+/// An event in a [`MidiFileTrack`]: Either a Midi message, a meta event or a system exclusive event
+#[derive(Debug, Clone, PartialEq)]
+pub enum MidiFileEvent {
+    Midi(MidiMsg),
+    Meta(MetaEvent),
+    /// A system exclusive message: The bytes following `F0`, normally ending with `F7`
+    SysEx(Vec<u8>),
+    /// An escape (`F7`) event: Bytes to be sent as-is, such as sysex continuation packets
+    Escape(Vec<u8>),
+}
+
+impl MidiFileEvent {
+    /// Whether the event is a note off, or a note on with a velocity of 0
+    pub fn is_note_off(&self) -> bool {
+        matches!(
+            self,
+            Self::Midi(MidiMsg::ChannelVoice {
+                msg: ChannelVoiceMsg::NoteOff { .. } | ChannelVoiceMsg::NoteOn { velocity: 0, .. },
+                ..
+            })
+        )
+    }
+
+    pub fn extend_midi(&self, v: &mut Vec<u8>) -> Result<(), Error> {
+        match self {
+            Self::Midi(msg) => {
+                msg.extend_midi(v);
+                Ok(())
+            }
+            Self::Meta(meta) => meta.extend_midi(v),
+            Self::SysEx(data) | Self::Escape(data) => {
+                v.push(if matches!(self, Self::SysEx(_)) {
+                    0xF0
+                } else {
+                    0xF7
+                });
+                push_vari(data.len() as u32, v)?;
+                v.extend_from_slice(data);
+                Ok(())
+            }
+        }
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+impl From<MidiMsg> for MidiFileEvent {
+    fn from(msg: MidiMsg) -> Self {
+        Self::Midi(msg)
+    }
+}
+
+impl From<MetaEvent> for MidiFileEvent {
+    fn from(meta: MetaEvent) -> Self {
+        Self::Meta(meta)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 #[repr(u16)]
 pub enum MidiFileFormat {
     SingleTrack = 0,
@@ -60,7 +352,28 @@ pub enum MidiFileFormat {
     IndependantTracks = 2,
 }
 
-#[derive(Debug)]
+/// How the events of a Midi file are encoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct WriteOptions {
+    /// Leave out the status byte of channel messages that have the same status as the
+    /// message before them (running status)
+    pub running_status: bool,
+    /// Write note offs as note ons with a velocity of 0, which lets them share a
+    /// running status with note ons. Their release velocity is lost.
+    pub note_off_as_note_on: bool,
+}
+
+impl WriteOptions {
+    /// The smallest encoding, for devices with little memory
+    pub fn compact() -> Self {
+        Self {
+            running_status: true,
+            note_off_as_note_on: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct MidiFile {
     pub ticks_per_quarter_note: u16,
     // TODO support subdivision-of-second delta-times
@@ -69,79 +382,443 @@ pub struct MidiFile {
 }
 
 impl MidiFile {
-    pub fn to_midi(&self) -> Vec<u8> {
+    /// Read a Standard Midi File of any format.
+    /// Chunks other than the header and tracks are skipped.
+    pub fn from_midi(bytes: &[u8]) -> Result<Self, Error> {
+        let mut reader = Reader::new(bytes);
+        if reader.take(4)? != b"MThd" {
+            return Err(Error::MissingHeader);
+        }
+        let header_len = reader.u32()? as usize;
+        let mut header = Reader::new(reader.take(header_len)?);
+        let format = match header.u16()? {
+            0 => MidiFileFormat::SingleTrack,
+            1 => MidiFileFormat::SimultaniousTracks,
+            2 => MidiFileFormat::IndependantTracks,
+            f => return Err(Error::InvalidFormat(f)),
+        };
+        let n_tracks = header.u16()? as usize;
+        let ticks_per_quarter_note = header.u16()?;
+        if ticks_per_quarter_note > 0x7FFF {
+            return Err(Error::UnsupportedTimeDivision(ticks_per_quarter_note));
+        }
+
+        let mut tracks = vec![];
+        while tracks.len() < n_tracks {
+            let chunk_type = reader.take(4)?;
+            let len = reader.u32()? as usize;
+            let chunk = reader.take(len)?;
+            if chunk_type == b"MTrk" {
+                tracks.push(MidiFileTrack::from_midi(chunk, tracks.len())?);
+            }
+        }
+
+        Ok(Self {
+            ticks_per_quarter_note,
+            format,
+            tracks,
+        })
+    }
+
+    pub fn to_midi(&self) -> Result<Vec<u8>, Error> {
+        self.to_midi_with(&WriteOptions::default())
+    }
+
+    pub fn to_midi_with(&self, options: &WriteOptions) -> Result<Vec<u8>, Error> {
         let mut r: Vec<u8> = vec![];
-        self.extend_midi(&mut r);
-        r
+        self.write_midi(&mut r, options)?;
+        Ok(r)
     }
 
-    pub fn track_to_midi(&self, track_num: usize) -> Vec<u8> {
+    pub fn track_to_midi(&self, track_num: usize) -> Result<Vec<u8>, Error> {
         let mut v: Vec<u8> = vec![];
-        v.extend_from_slice(b"MThd");
-        push_u32(6, &mut v); // Length of header, always 6 bytes
-        push_u16(self.format as u16, &mut v);
-        push_u16(1_u16, &mut v); // num tracks
-        if self.ticks_per_quarter_note > 0x7FFF {
-            panic!("Ticks per quarter note must be less than {}", 0x7FFF);
+        self.write_header(1, &mut v)?;
+        self.track_file(track_num)?.tracks[0].write_midi(
+            track_num,
+            &mut v,
+            &WriteOptions::default(),
+        )?;
+        Ok(v)
+    }
+
+    /// A file holding only the given track
+    pub fn track_file(&self, track_num: usize) -> Result<MidiFile, Error> {
+        let mut track = self
+            .tracks
+            .get(track_num)
+            .ok_or(Error::TrackNotFound(track_num))?
+            .clone();
+        if track_num != 0 {
+            // The tempo map, time signature and markers live in the first track, so carry them over
+            // Don't let the carried over events extend the track
+            let mut events: Vec<(u32, MidiFileEvent)> = self.tracks[0]
+                .events
+                .iter()
+                .filter(|(tick, e)| matches!(e, MidiFileEvent::Meta(_)) && *tick <= track.n_ticks)
+                .cloned()
+                .collect();
+            events.append(&mut track.events);
+            track.events = events;
+            track.sort_events();
         }
-        push_u16(self.ticks_per_quarter_note, &mut v);
-        self.tracks[track_num].extend_midi(&mut v);
+        Ok(MidiFile {
+            ticks_per_quarter_note: self.ticks_per_quarter_note,
+            format: self.format,
+            tracks: vec![track],
+        })
+    }
+
+    /// Merge all tracks into the single track of a format 0 file.
+    /// Events at the same tick keep the order of their tracks, and the first track's name
+    /// names the merged track. The names of the other tracks are kept as text events.
+    /// Notes of tracks that share a channel can't be told apart once merged.
+    pub fn to_single_track(&self) -> MidiFile {
+        let mut track = MidiFileTrack {
+            events: vec![],
+            name: self.tracks.first().and_then(|t| t.name.clone()),
+            n_ticks: self.tracks.iter().map(|t| t.n_ticks).max().unwrap_or(0),
+        };
+        for (i, t) in self.tracks.iter().enumerate() {
+            if let Some(name) = t.name.as_ref().filter(|_| i > 0) {
+                track.events.push((0, MetaEvent::Text(name.clone()).into()));
+            }
+            track.events.extend(t.events.iter().cloned());
+        }
+        // Sorting is stable, so other than note offs, events of a track stay after those
+        // of the tracks before it
+        track.sort_events();
+        MidiFile {
+            ticks_per_quarter_note: self.ticks_per_quarter_note,
+            format: MidiFileFormat::SingleTrack,
+            tracks: vec![track],
+        }
+    }
 
-        v
+    /// Write the file to `v`. Nothing is written if the file is invalid,
+    /// but `v` may be left partially written if writing fails.
+    pub fn extend_midi(&self, v: &mut Vec<u8>) -> Result<(), Error> {
+        self.write_midi(v, &WriteOptions::default())
     }
 
-    pub fn extend_midi(&self, v: &mut Vec<u8>) {
-        v.extend_from_slice(b"MThd");
-        push_u32(6, v); // Length of header, always 6 bytes
-        push_u16(self.format as u16, v);
-        push_u16(self.tracks.len() as u16, v); // num tracks
+    /// Write the file to `w` as it is encoded, without buffering it. Nothing is written
+    /// if the file is invalid, but `w` may be left partially written if writing fails.
+    /// Writes are small, so slow writers such as files are best wrapped in a [`io::BufWriter`].
+    pub fn write_midi<W: Write>(&self, w: &mut W, options: &WriteOptions) -> Result<(), Error> {
+        self.validate()?;
+        self.write_header(self.tracks.len(), w)?;
+        for (i, track) in self.tracks.iter().enumerate() {
+            track.write_midi(i, w, options)?;
+        }
+        Ok(())
+    }
+
+    /// Check that the file can be written
+    pub fn validate(&self) -> Result<(), Error> {
         if self.ticks_per_quarter_note > 0x7FFF {
-            panic!("Ticks per quarter note must be less than {}", 0x7FFF);
+            return Err(Error::InvalidTicksPerQuarterNote(
+                self.ticks_per_quarter_note,
+            ));
+        }
+        if self.tracks.len() > 0xFFFF {
+            return Err(Error::TooManyTracks(self.tracks.len()));
+        }
+        if self.format == MidiFileFormat::SingleTrack && self.tracks.len() != 1 {
+            return Err(Error::SingleTrackFormat(self.tracks.len()));
         }
-        push_u16(self.ticks_per_quarter_note, v);
-        for track in self.tracks.iter() {
-            track.extend_midi(v);
+        for (i, track) in self.tracks.iter().enumerate() {
+            track.validate(i)?;
         }
+        Ok(())
+    }
+
+    fn write_header<W: Write>(&self, n_tracks: usize, w: &mut W) -> Result<(), Error> {
+        if self.ticks_per_quarter_note > 0x7FFF {
+            return Err(Error::InvalidTicksPerQuarterNote(
+                self.ticks_per_quarter_note,
+            ));
+        }
+        let mut v = Vec::with_capacity(14);
+        v.extend_from_slice(b"MThd");
+        push_u32(6, &mut v); // Length of header, always 6 bytes
+        push_u16(self.format as u16, &mut v);
+        push_u16(n_tracks as u16, &mut v); // num tracks
+        push_u16(self.ticks_per_quarter_note, &mut v);
+        w.write_all(&v)?;
+        Ok(())
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct MidiFileTrack {
-    /// A vector of tick/Midi event tuples.
+    /// A vector of tick/event tuples.
     /// Unlike in the Midi file representation, the ticks in the tuple represent
     /// absolute time since the start of the track. Events must be in order.
-    pub events: Vec<(u32, MidiMsg)>,
+    pub events: Vec<(u32, MidiFileEvent)>,
     /// Name of the track
     pub name: Option<String>,
     /// Length of the track in ticks
     pub n_ticks: u32,
 }
 impl MidiFileTrack {
-    pub fn extend_midi(&self, v: &mut Vec<u8>) {
-        v.extend_from_slice(b"MTrk");
-        let mut events: Vec<u8> = vec![];
+    /// Read the events of a `MTrk` chunk, not including its type and length.
+    /// `track_num` is only used for error reporting.
+    pub fn from_midi(bytes: &[u8], track_num: usize) -> Result<Self, Error> {
+        let mut reader = Reader::new(bytes);
+        let mut track = Self {
+            events: vec![],
+            name: None,
+            n_ticks: 0,
+        };
+        let mut ticks: u32 = 0;
+        let mut running_status: Option<u8> = None;
+        while !reader.is_empty() {
+            ticks = ticks.saturating_add(reader.vari()?);
+            let offset = reader.pos;
+            let mut status = reader.u8()?;
+            if status < 0x80 {
+                // Running status: Reuse the last channel message status
+                status = running_status.ok_or(Error::MissingStatus {
+                    track: track_num,
+                    offset,
+                })?;
+                reader.pos -= 1;
+            }
+            match status {
+                0xFF => {
+                    running_status = None;
+                    let meta_type = reader.u8()?;
+                    let len = reader.vari()? as usize;
+                    let data = reader.take(len)?;
+                    match meta_type {
+                        0x2F => {
+                            // Files written by `write_midi` read back a tick longer,
+                            // since it puts the End of Track event a tick after the end
+                            track.n_ticks = ticks;
+                            return Ok(track);
+                        }
+                        0x03 if track.name.is_none() => {
+                            track.name = Some(String::from_utf8_lossy(data).into_owned())
+                        }
+                        _ => track
+                            .events
+                            .push((ticks, MetaEvent::from_data(meta_type, data).into())),
+                    }
+                }
+                0xF0 | 0xF7 => {
+                    running_status = None;
+                    let len = reader.vari()? as usize;
+                    let data = reader.take(len)?.to_vec();
+                    let event = if status == 0xF0 {
+                        MidiFileEvent::SysEx(data)
+                    } else {
+                        MidiFileEvent::Escape(data)
+                    };
+                    track.events.push((ticks, event));
+                }
+                0x80..=0xEF => {
+                    running_status = Some(status);
+                    let channel = Channel::from_u8(status & 0x0F);
+                    let mut data = || -> Result<u8, Error> { Ok(reader.u8()? & 0x7F) };
+                    let msg = match status & 0xF0 {
+                        0x80 => ChannelVoiceMsg::NoteOff {
+                            note: data()?,
+                            velocity: data()?,
+                        },
+                        0x90 => ChannelVoiceMsg::NoteOn {
+                            note: data()?,
+                            velocity: data()?,
+                        },
+                        0xA0 => ChannelVoiceMsg::PolyPressure {
+                            note: data()?,
+                            pressure: data()?,
+                        },
+                        0xB0 => ChannelVoiceMsg::ControlChange {
+                            control: midi_msg::ControlChange::Undefined {
+                                control: data()?,
+                                value: data()?,
+                            },
+                        },
+                        0xC0 => ChannelVoiceMsg::ProgramChange { program: data()? },
+                        0xD0 => ChannelVoiceMsg::ChannelPressure { pressure: data()? },
+                        _ => {
+                            let lsb = data()? as u16;
+                            let msb = data()? as u16;
+                            ChannelVoiceMsg::PitchBend {
+                                bend: (msb << 7) | lsb,
+                            }
+                        }
+                    };
+                    track
+                        .events
+                        .push((ticks, MidiMsg::ChannelVoice { channel, msg }.into()));
+                }
+                _ => {
+                    return Err(Error::InvalidStatus {
+                        track: track_num,
+                        offset,
+                        status,
+                    })
+                }
+            }
+        }
+        // Tolerate tracks that are missing their End of Track event
+        track.n_ticks = ticks;
+        Ok(track)
+    }
+
+    /// Check that events are sorted and fall within the length of the track.
+    /// `track_num` is only used for error reporting.
+    pub fn validate(&self, track_num: usize) -> Result<(), Error> {
+        let mut last_tick = 0;
+        for (tick, _) in self.events.iter() {
+            if *tick < last_tick {
+                return Err(Error::UnsortedEvents {
+                    track: track_num,
+                    tick: *tick,
+                    previous_tick: last_tick,
+                });
+            }
+            last_tick = *tick;
+        }
+        if last_tick > self.n_ticks {
+            return Err(Error::EventAfterEnd {
+                track: track_num,
+                tick: last_tick,
+                n_ticks: self.n_ticks,
+            });
+        }
+        Ok(())
+    }
+
+    /// Write the track chunk to `v`, after validating it.
+    /// `track_num` is only used for error reporting.
+    pub fn extend_midi(&self, track_num: usize, v: &mut Vec<u8>) -> Result<(), Error> {
+        self.write_midi(track_num, v, &WriteOptions::default())
+    }
+
+    /// Write the track chunk to `w`, after validating it. The events are encoded twice:
+    /// Once to find the length of the chunk, and once as they are written.
+    /// `track_num` is only used for error reporting.
+    pub fn write_midi<W: Write>(
+        &self,
+        track_num: usize,
+        w: &mut W,
+        options: &WriteOptions,
+    ) -> Result<(), Error> {
+        self.validate(track_num)?;
+        let mut len = 0;
+        self.encode_events(options, |bytes| {
+            len += bytes.len();
+            Ok(())
+        })?;
+        w.write_all(b"MTrk")?;
+        w.write_all(&u32_to_bytes(len as u32))?;
+        self.encode_events(options, |bytes| Ok(w.write_all(bytes)?))
+    }
 
+    /// Encode the events of the track chunk one at a time, passing the bytes of each to `out`
+    fn encode_events(
+        &self,
+        options: &WriteOptions,
+        mut out: impl FnMut(&[u8]) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let mut v: Vec<u8> = vec![];
         if let Some(name) = &self.name {
             let len = name.len().min(127);
-            events.extend_from_slice(&[0x00, 0xFF, 0x03, len as u8]);
-            events.extend_from_slice(&name.as_bytes()[..len]);
+            v.extend_from_slice(&[0x00, 0xFF, 0x03, len as u8]);
+            v.extend_from_slice(&name.as_bytes()[..len]);
+            out(&v)?;
         }
 
         let mut last_tick = 0;
-        for (ticks, msg) in self.events.iter() {
-            push_vari(*ticks - last_tick, &mut events);
-            msg.extend_midi(&mut events);
+        let mut running_status = None;
+        for (ticks, event) in self.events.iter() {
+            v.clear();
+            push_vari(*ticks - last_tick, &mut v)?;
+            let status_pos = v.len();
+            match event {
+                MidiFileEvent::Midi(MidiMsg::ChannelVoice {
+                    channel,
+                    msg: ChannelVoiceMsg::NoteOff { note, .. },
+                }) if options.note_off_as_note_on => MidiMsg::ChannelVoice {
+                    channel: *channel,
+                    msg: ChannelVoiceMsg::NoteOn {
+                        note: *note,
+                        velocity: 0,
+                    },
+                }
+                .extend_midi(&mut v),
+                _ => event.extend_midi(&mut v)?,
+            }
+            // Only channel messages can use running status. Anything else cancels it.
+            match v.get(status_pos).copied() {
+                Some(status @ 0x80..=0xEF) => {
+                    if options.running_status && running_status == Some(status) {
+                        v.remove(status_pos);
+                    }
+                    running_status = Some(status);
+                }
+                _ => running_status = None,
+            }
+            out(&v)?;
             last_tick = *ticks;
         }
-        push_vari(self.n_ticks - last_tick + 1, &mut events);
-        events.extend_from_slice(&[0xFF, 0x2F, 0x00]);
-        push_u32(events.len() as u32, v);
-        v.extend_from_slice(&events);
+        v.clear();
+        // End of Track goes a tick after the end, so events on the last tick aren't cut off
+        push_vari((self.n_ticks - last_tick).saturating_add(1), &mut v)?;
+        v.extend_from_slice(&[0xFF, 0x2F, 0x00]);
+        out(&v)
     }
 
-    /// Sort events by tick time
+    /// Sort events by tick time. Note offs go before the other events of their tick,
+    /// so that a note ending where a note of the same pitch starts doesn't cut it off.
+    /// The sort is stable, so events are otherwise kept in the order they were added.
     pub fn sort_events(&mut self) {
-        self.events.sort_by(|a, b| a.0.cmp(&b.0));
+        self.events
+            .sort_by_key(|(tick, event)| (*tick, !event.is_note_off()));
+    }
+}
+
+/// A cursor over Midi data
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, pos: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.bytes.len()
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        let bytes = self
+            .bytes
+            .get(self.pos..self.pos.saturating_add(n))
+            .ok_or(Error::UnexpectedEof)?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        Ok(BigEndian::read_u16(self.take(2)?))
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        Ok(BigEndian::read_u32(self.take(4)?))
+    }
+
+    fn vari(&mut self) -> Result<u32, Error> {
+        let (x, len) = read_vari(&self.bytes[self.pos.min(self.bytes.len())..])?;
+        self.pos += len;
+        Ok(x)
     }
 }
 
@@ -149,6 +826,14 @@ impl MidiFileTrack {
 mod tests {
     use crate::midi_file::*;
 
+    /// `midi_file` as it reads back after being written, a tick longer
+    fn written(mut midi_file: MidiFile) -> MidiFile {
+        for track in midi_file.tracks.iter_mut() {
+            track.n_ticks += 1;
+        }
+        midi_file
+    }
+
     #[test]
     fn test_u32_to_bytes() {
         let mut v: Vec<u8> = vec![];
@@ -158,7 +843,7 @@ mod tests {
 
     fn validate_vari(n: u32, bytes: Vec<u8>) {
         let mut v: Vec<u8> = vec![];
-        push_vari(n, &mut v);
+        push_vari(n, &mut v).unwrap();
         assert_eq!(
             v, bytes,
             "{:#02X} should have been {:#02X?} not {:#02X?}",
@@ -180,6 +865,491 @@ mod tests {
         validate_vari(0x0FFFFFFF, vec![0xFF, 0xFF, 0xFF, 0x7F]);
     }
 
+    #[test]
+    fn test_read_vari() {
+        for n in [0, 6, 0x7F, 0x80, 0x3FFF, 0x4000, 0x1FFFFF, 0x0FFFFFFF] {
+            let mut v: Vec<u8> = vec![];
+            push_vari(n, &mut v).unwrap();
+            assert_eq!(read_vari(&v), Ok((n, v.len())));
+        }
+        assert_eq!(read_vari(&[0x81, 0x00, 0x05]), Ok((0x80, 2)));
+        assert_eq!(read_vari(&[0x81]), Err(Error::UnexpectedEof));
+        assert_eq!(
+            read_vari(&[0x81, 0x81, 0x81, 0x81, 0x00]),
+            Err(Error::InvalidVariableLength)
+        );
+    }
+
+    #[test]
+    fn test_write_errors() {
+        let mut v: Vec<u8> = vec![];
+        assert_eq!(
+            push_vari(0x10000000, &mut v),
+            Err(Error::VariableLengthTooLarge(0x10000000))
+        );
+
+        let note = |tick| {
+            (
+                tick,
+                MidiMsg::ChannelVoice {
+                    channel: Channel::Ch1,
+                    msg: ChannelVoiceMsg::NoteOn {
+                        note: 60,
+                        velocity: 100,
+                    },
+                }
+                .into(),
+            )
+        };
+        let mut midi_file = MidiFile {
+            format: MidiFileFormat::SimultaniousTracks,
+            ticks_per_quarter_note: 0x8000,
+            tracks: vec![MidiFileTrack {
+                name: None,
+                events: vec![note(10), note(5)],
+                n_ticks: 8,
+            }],
+        };
+        assert_eq!(
+            midi_file.to_midi(),
+            Err(Error::InvalidTicksPerQuarterNote(0x8000))
+        );
+        midi_file.ticks_per_quarter_note = 24;
+        assert_eq!(
+            midi_file.to_midi(),
+            Err(Error::UnsortedEvents {
+                track: 0,
+                tick: 5,
+                previous_tick: 10
+            })
+        );
+        midi_file.tracks[0].sort_events();
+        assert_eq!(
+            midi_file.to_midi(),
+            Err(Error::EventAfterEnd {
+                track: 0,
+                tick: 10,
+                n_ticks: 8
+            })
+        );
+        assert_eq!(midi_file.track_to_midi(1), Err(Error::TrackNotFound(1)));
+        midi_file.tracks[0].n_ticks = 10;
+        assert!(midi_file.to_midi().is_ok());
+    }
+
+    #[test]
+    fn test_write_midi() {
+        let midi_file = MidiFile {
+            format: MidiFileFormat::SimultaniousTracks,
+            ticks_per_quarter_note: 96,
+            tracks: vec![MidiFileTrack {
+                name: Some("Track".to_string()),
+                events: vec![(0, MetaEvent::tempo_from_bpm(120.0).into())],
+                n_ticks: 96,
+            }],
+        };
+        let mut v = std::io::Cursor::new(vec![]);
+        midi_file
+            .write_midi(&mut v, &WriteOptions::default())
+            .unwrap();
+        assert_eq!(v.into_inner(), midi_file.to_midi().unwrap());
+
+        /// Accepts a number of bytes, then fails
+        struct Full(usize);
+        impl Write for Full {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                if self.0 == 0 {
+                    return Err(io::Error::new(io::ErrorKind::WriteZero, "full"));
+                }
+                let n = buf.len().min(self.0);
+                self.0 -= n;
+                Ok(n)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        assert_eq!(
+            midi_file.write_midi(&mut Full(20), &WriteOptions::default()),
+            Err(Error::Io {
+                kind: io::ErrorKind::WriteZero,
+                message: "full".to_string()
+            })
+        );
+
+        // Nothing is written for invalid files
+        let mut invalid = midi_file.clone();
+        invalid.tracks[0]
+            .events
+            .push((97, MetaEvent::Text("Late".to_string()).into()));
+        let mut v = vec![];
+        assert!(invalid
+            .write_midi(&mut v, &WriteOptions::default())
+            .is_err());
+        assert!(v.is_empty());
+    }
+
+    #[test]
+    fn test_sort_events() {
+        let note = |note, velocity| -> MidiFileEvent {
+            MidiMsg::ChannelVoice {
+                channel: Channel::Ch1,
+                msg: ChannelVoiceMsg::NoteOn { note, velocity },
+            }
+            .into()
+        };
+        let note_off: MidiFileEvent = MidiMsg::ChannelVoice {
+            channel: Channel::Ch1,
+            msg: ChannelVoiceMsg::NoteOff {
+                note: 0x3C,
+                velocity: 0,
+            },
+        }
+        .into();
+        let text = |t: &str| -> MidiFileEvent { MetaEvent::Text(t.to_string()).into() };
+        let mut track = MidiFileTrack {
+            name: None,
+            events: vec![
+                (24, note(0x3C, 0x40)),
+                (24, text("a")),
+                (0, note(0x3C, 0x40)),
+                (24, note_off.clone()),
+                (24, text("b")),
+                (24, note(0x3E, 0)),
+            ],
+            n_ticks: 24,
+        };
+        track.sort_events();
+        assert_eq!(
+            track.events,
+            vec![
+                (0, note(0x3C, 0x40)),
+                (24, note_off),
+                (24, note(0x3E, 0)),
+                (24, note(0x3C, 0x40)),
+                (24, text("a")),
+                (24, text("b")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_write_compact() {
+        let note = |note, on| -> MidiFileEvent {
+            MidiMsg::ChannelVoice {
+                channel: Channel::Ch1,
+                msg: if on {
+                    ChannelVoiceMsg::NoteOn { note, velocity: 64 }
+                } else {
+                    ChannelVoiceMsg::NoteOff { note, velocity: 64 }
+                },
+            }
+            .into()
+        };
+        let midi_file = MidiFile {
+            format: MidiFileFormat::SingleTrack,
+            ticks_per_quarter_note: 24,
+            tracks: vec![MidiFileTrack {
+                name: None,
+                events: vec![
+                    (0, note(0x3C, true)),
+                    (0, note(0x40, true)),
+                    (24, note(0x3C, false)),
+                    (24, MetaEvent::Text("a".to_string()).into()),
+                    (48, note(0x40, false)),
+                ],
+                n_ticks: 48,
+            }],
+        };
+
+        let bytes = midi_file.to_midi_with(&WriteOptions::compact()).unwrap();
+        assert_eq!(
+            bytes[22..],
+            [
+                0x00, 0x90, 0x3C, 0x40, // Note on
+                0x00, 0x40, 0x40, // Running status note on
+                0x18, 0x3C, 0x00, // Note off as a running status note on
+                0x00, 0xFF, 0x01, 0x01, 0x61, // Text
+                0x18, 0x90, 0x40, 0x00, // Status repeated after a meta event
+                0x01, 0xFF, 0x2F, 0x00, // End of track
+            ]
+        );
+        assert!(bytes.len() < midi_file.to_midi().unwrap().len());
+
+        let read = MidiFile::from_midi(&bytes).unwrap();
+        assert_eq!(read.tracks[0].events.len(), 5);
+        assert_eq!(read.tracks[0].n_ticks, 49);
+        assert_eq!(read.tracks[0].events[4].1, {
+            MidiMsg::ChannelVoice {
+                channel: Channel::Ch1,
+                msg: ChannelVoiceMsg::NoteOn {
+                    note: 0x40,
+                    velocity: 0,
+                },
+            }
+            .into()
+        });
+
+        // Each option works on its own
+        let running_status = WriteOptions {
+            running_status: true,
+            ..Default::default()
+        };
+        let bytes = midi_file.to_midi_with(&running_status).unwrap();
+        assert_eq!(bytes[26..29], [0x00, 0x40, 0x40]);
+        assert_eq!(bytes[29..33], [0x18, 0x80, 0x3C, 0x40]);
+        let note_on = WriteOptions {
+            note_off_as_note_on: true,
+            ..Default::default()
+        };
+        let bytes = midi_file.to_midi_with(&note_on).unwrap();
+        assert_eq!(bytes[30..34], [0x18, 0x90, 0x3C, 0x00]);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let note_on = |note| -> MidiFileEvent {
+            MidiMsg::ChannelVoice {
+                channel: Channel::Ch3,
+                msg: ChannelVoiceMsg::NoteOn { note, velocity: 64 },
+            }
+            .into()
+        };
+        let midi_file = MidiFile {
+            format: MidiFileFormat::SimultaniousTracks,
+            ticks_per_quarter_note: 96,
+            tracks: vec![
+                MidiFileTrack {
+                    name: Some("Conductor".to_string()),
+                    events: vec![
+                        (
+                            0,
+                            MetaEvent::TimeSignature {
+                                numerator: 7,
+                                denominator: 8,
+                            }
+                            .into(),
+                        ),
+                        (0, MetaEvent::tempo_from_bpm(140.0).into()),
+                        (0, MetaEvent::Marker("Row 00".to_string()).into()),
+                        (
+                            0,
+                            MetaEvent::Other {
+                                meta_type: 0x59,
+                                data: vec![0xFE, 0x01],
+                            }
+                            .into(),
+                        ),
+                        (384, MetaEvent::CuePoint("Chain 01".to_string()).into()),
+                    ],
+                    n_ticks: 400,
+                },
+                MidiFileTrack {
+                    name: None,
+                    events: vec![
+                        (0, MidiFileEvent::SysEx(vec![0x7E, 0x7F, 0x09, 0x01, 0xF7])),
+                        (0, note_on(60)),
+                        (
+                            0x4000,
+                            MidiMsg::ChannelVoice {
+                                channel: Channel::Ch3,
+                                msg: ChannelVoiceMsg::PitchBend { bend: 0x1234 },
+                            }
+                            .into(),
+                        ),
+                        (0x4001, note_on(62)),
+                    ],
+                    n_ticks: 0x5000,
+                },
+            ],
+        };
+        assert_eq!(
+            MidiFile::from_midi(&midi_file.to_midi().unwrap()),
+            Ok(written(midi_file))
+        );
+    }
+
+    #[test]
+    fn test_to_single_track() {
+        let note_on = |channel, note| -> MidiFileEvent {
+            MidiMsg::ChannelVoice {
+                channel,
+                msg: ChannelVoiceMsg::NoteOn { note, velocity: 64 },
+            }
+            .into()
+        };
+        let track = |name: &str, events, n_ticks| MidiFileTrack {
+            name: Some(name.to_string()),
+            events,
+            n_ticks,
+        };
+        let midi_file = MidiFile {
+            format: MidiFileFormat::SimultaniousTracks,
+            ticks_per_quarter_note: 96,
+            tracks: vec![
+                track(
+                    "Song",
+                    vec![(0, MetaEvent::tempo_from_bpm(120.0).into())],
+                    96,
+                ),
+                track(
+                    "Song_1",
+                    vec![
+                        (0, note_on(Channel::Ch1, 60)),
+                        (48, note_on(Channel::Ch1, 62)),
+                    ],
+                    96,
+                ),
+                track("Song_2", vec![(48, note_on(Channel::Ch2, 64))], 192),
+            ],
+        };
+        let single = midi_file.to_single_track();
+        assert_eq!(single.format, MidiFileFormat::SingleTrack);
+        assert_eq!(single.tracks.len(), 1);
+        assert_eq!(single.tracks[0].name, Some("Song".to_string()));
+        assert_eq!(single.tracks[0].n_ticks, 192);
+        assert_eq!(
+            single.tracks[0].events,
+            vec![
+                (0, MetaEvent::tempo_from_bpm(120.0).into()),
+                (0, MetaEvent::Text("Song_1".to_string()).into()),
+                (0, note_on(Channel::Ch1, 60)),
+                (0, MetaEvent::Text("Song_2".to_string()).into()),
+                (48, note_on(Channel::Ch1, 62)),
+                (48, note_on(Channel::Ch2, 64)),
+            ]
+        );
+        assert_eq!(
+            MidiFile::from_midi(&single.to_midi().unwrap()),
+            Ok(written(single.clone()))
+        );
+
+        // Format 0 files can only hold one track
+        let invalid = MidiFile {
+            format: MidiFileFormat::SingleTrack,
+            ..midi_file.clone()
+        };
+        assert_eq!(invalid.to_midi(), Err(Error::SingleTrackFormat(3)));
+        // A single track is left as it is
+        let track_file = midi_file.track_file(2).unwrap();
+        assert_eq!(track_file.to_single_track().tracks, track_file.tracks);
+    }
+
+    #[test]
+    fn test_read_running_status() {
+        let single_track = |events: &[u8]| {
+            let mut bytes = vec![];
+            bytes.extend_from_slice(b"MThd");
+            push_u32(6, &mut bytes);
+            bytes.extend_from_slice(&[0, 0, 0, 1, 0, 24]);
+            bytes.extend_from_slice(b"MTrk");
+            push_u32(events.len() as u32, &mut bytes);
+            bytes.extend_from_slice(events);
+            bytes
+        };
+        let events = [
+            0x00, 0x91, 0x3C, 0x40, // Note on
+            0x06, 0x3C, 0x00, // Running status note on
+            0x00, 0xFF, 0x01, 0x02, 0x68, 0x69, // Text
+            0x00, 0x40, 0x00, // Data byte after a meta event
+        ];
+
+        assert_eq!(
+            MidiFile::from_midi(&single_track(&events)),
+            Err(Error::MissingStatus {
+                track: 0,
+                offset: 14
+            })
+        );
+
+        let midi_file = MidiFile::from_midi(&single_track(&events[..13])).unwrap();
+        assert_eq!(midi_file.format, MidiFileFormat::SingleTrack);
+        assert_eq!(
+            midi_file.tracks[0].events,
+            vec![
+                (
+                    0,
+                    MidiMsg::ChannelVoice {
+                        channel: Channel::Ch2,
+                        msg: ChannelVoiceMsg::NoteOn {
+                            note: 0x3C,
+                            velocity: 0x40
+                        }
+                    }
+                    .into()
+                ),
+                (
+                    6,
+                    MidiMsg::ChannelVoice {
+                        channel: Channel::Ch2,
+                        msg: ChannelVoiceMsg::NoteOn {
+                            note: 0x3C,
+                            velocity: 0
+                        }
+                    }
+                    .into()
+                ),
+                (6, MetaEvent::Text("hi".to_string()).into()),
+            ]
+        );
+        assert_eq!(midi_file.tracks[0].n_ticks, 6);
+    }
+
+    #[test]
+    fn test_read_errors() {
+        assert_eq!(MidiFile::from_midi(b"RIFF"), Err(Error::MissingHeader));
+        assert_eq!(MidiFile::from_midi(b"MThd"), Err(Error::UnexpectedEof));
+        assert_eq!(
+            MidiFile::from_midi(&[b'M', b'T', b'h', b'd', 0, 0, 0, 6, 0, 3, 0, 0, 0, 24]),
+            Err(Error::InvalidFormat(3))
+        );
+        assert_eq!(
+            MidiFile::from_midi(&[b'M', b'T', b'h', b'd', 0, 0, 0, 6, 0, 1, 0, 0, 0xE7, 0x28]),
+            Err(Error::UnsupportedTimeDivision(0xE728))
+        );
+        assert_eq!(
+            MidiFileTrack::from_midi(&[0x00, 0xF2, 0x00, 0x00], 2),
+            Err(Error::InvalidStatus {
+                track: 2,
+                offset: 1,
+                status: 0xF2
+            })
+        );
+    }
+
+    #[test]
+    fn test_tempo_event() {
+        let mut v: Vec<u8> = vec![];
+        MetaEvent::tempo_from_bpm(120.0)
+            .extend_midi(&mut v)
+            .unwrap();
+        assert_eq!(v, vec![0xFF, 0x51, 0x03, 0x07, 0xA1, 0x20]);
+    }
+
+    #[test]
+    fn test_time_signature_event() {
+        let mut v: Vec<u8> = vec![];
+        MetaEvent::TimeSignature {
+            numerator: 6,
+            denominator: 8,
+        }
+        .extend_midi(&mut v)
+        .unwrap();
+        assert_eq!(v, vec![0xFF, 0x58, 0x04, 0x06, 0x03, 0x0C, 0x08]);
+    }
+
+    #[test]
+    fn test_marker_event() {
+        let mut v: Vec<u8> = vec![];
+        MetaEvent::Marker("Row 0A".to_string())
+            .extend_midi(&mut v)
+            .unwrap();
+        assert_eq!(
+            v,
+            vec![0xFF, 0x06, 0x06, 0x52, 0x6F, 0x77, 0x20, 0x30, 0x41]
+        );
+    }
+
     #[test]
     fn test_midi_file() {
         let quarter_note: u32 = 960;
@@ -198,7 +1368,8 @@ mod tests {
                                     note: 72,
                                     velocity: 100,
                                 },
-                            },
+                            }
+                            .into(),
                         ),
                         (
                             quarter_note / 8,
@@ -208,7 +1379,8 @@ mod tests {
                                     note: 72,
                                     velocity: 100,
                                 },
-                            },
+                            }
+                            .into(),
                         ),
                     ],
                     n_ticks: quarter_note * 4,
@@ -224,7 +1396,8 @@ mod tests {
                                     note: 72,
                                     velocity: 100,
                                 },
-                            },
+                            }
+                            .into(),
                         ),
                         (
                             quarter_note / 8,
@@ -234,14 +1407,16 @@ mod tests {
                                     note: 72,
                                     velocity: 100,
                                 },
-                            },
+                            }
+                            .into(),
                         ),
                     ],
                     n_ticks: quarter_note * 4,
                 },
             ],
         }
-        .to_midi();
+        .to_midi()
+        .unwrap();
 
         assert_eq!(
             &midi_file,
@@ -0,0 +1,150 @@
+use std::fmt;
+
+use serde::Serialize;
+
+/// Where a step is in the song
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub struct Location {
+    /// Song row. Chains and phrases rendered on their own have none.
+    pub row: Option<u8>,
+    /// Chains rendered as part of the song or on their own. Phrases rendered on their own have none.
+    pub chain: Option<u8>,
+    pub phrase: u8,
+    pub step: u8,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(row) = self.row {
+            write!(f, "row {:02X}, ", row)?;
+        }
+        if let Some(chain) = self.chain {
+            write!(f, "chain {:02X}, ", chain)?;
+        }
+        write!(f, "phrase {:02X}, step {:X}", self.phrase, self.step)
+    }
+}
+
+/// An FX command that has no effect on the Midi output
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct UnsupportedFx {
+    pub location: Location,
+    /// Name of the command, or its number in hex if it isn't known
+    pub command: String,
+    pub value: u8,
+}
+
+impl fmt::Display for UnsupportedFx {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: unsupported FX command {} {:02X}",
+            self.location, self.command, self.value
+        )
+    }
+}
+
+/// A note that was transposed outside of the Midi note range
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct OutOfRangeNote {
+    pub location: Location,
+    /// The transposed note
+    pub note: i16,
+    /// The note that was output instead, if any
+    pub output: Option<u8>,
+}
+
+impl fmt::Display for OutOfRangeNote {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: note {} is out of the Midi note range",
+            self.location, self.note
+        )?;
+        match self.output {
+            Some(note) => write!(f, " and was output as {}", note),
+            None => write!(f, " and was dropped"),
+        }
+    }
+}
+
+/// A note that was cut short by the max note length
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct TruncatedNote {
+    pub location: Location,
+    pub note: u8,
+}
+
+impl fmt::Display for TruncatedNote {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: note {} was cut short by the max note length",
+            self.location, self.note
+        )
+    }
+}
+
+/// What happened while rendering a song track
+#[derive(Debug, Clone, PartialEq, Default, Serialize)]
+pub struct TrackReport {
+    /// Song track number (1-8)
+    pub track: usize,
+    /// Number of notes output
+    pub notes: usize,
+    /// Length of the track, in bars of the output time signature
+    pub bars: f32,
+    pub unsupported_fx: Vec<UnsupportedFx>,
+    pub out_of_range_notes: Vec<OutOfRangeNote>,
+    pub truncated_notes: Vec<TruncatedNote>,
+}
+
+impl fmt::Display for TrackReport {
+    /// A one line summary of the track
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let plural = |n: usize| if n == 1 { "" } else { "s" };
+        write!(
+            f,
+            "Track {}: {} note{}, {:.1} bars",
+            self.track,
+            self.notes,
+            plural(self.notes),
+            self.bars
+        )?;
+        for (n, description) in [
+            (self.unsupported_fx.len(), "unsupported FX command"),
+            (self.out_of_range_notes.len(), "out of range note"),
+            (self.truncated_notes.len(), "truncated note"),
+        ] {
+            if n > 0 {
+                write!(f, ", {} {}{}", n, description, plural(n))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What happened while converting a song, for each of the rendered song tracks
+#[derive(Debug, Clone, PartialEq, Default, Serialize)]
+pub struct ConversionReport {
+    pub tracks: Vec<TrackReport>,
+}
+
+impl fmt::Display for ConversionReport {
+    /// A summary of each track, followed by everything that went wrong in it
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for track in self.tracks.iter() {
+            writeln!(f, "{}", track)?;
+            for fx in track.unsupported_fx.iter() {
+                writeln!(f, "  {}", fx)?;
+            }
+            for note in track.out_of_range_notes.iter() {
+                writeln!(f, "  {}", note)?;
+            }
+            for note in track.truncated_notes.iter() {
+                writeln!(f, "  {}", note)?;
+            }
+        }
+        Ok(())
+    }
+}
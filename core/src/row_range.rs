@@ -0,0 +1,152 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// A range of song rows to render, from `start` up to but not including `end`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RowRange {
+    start: u8,
+    end: Option<u8>,
+}
+
+impl RowRange {
+    /// Rows from `start` to `end` (exclusive), or until the end of the song if `end` is `None`.
+    /// `end` must be after `start`.
+    pub fn new(start: u8, end: Option<u8>) -> Result<Self, String> {
+        match end {
+            Some(end) if end <= start => Err(format!(
+                "Invalid row range. The end row {:02X} must be after the start row {:02X}",
+                end, start
+            )),
+            _ => Ok(Self { start, end }),
+        }
+    }
+
+    /// First row played
+    pub fn start(&self) -> u8 {
+        self.start
+    }
+
+    /// Row at which to stop, exclusive. Tracks that loop go back to no earlier than `start`.
+    pub fn end(&self) -> Option<u8> {
+        self.end
+    }
+
+    /// Parses a row number. Rows are hex, as they are shown on the M8 (`1F`, `0x1F` or `$1F`),
+    /// so `10` is row 16. Decimal rows take a `0d` prefix (`0d31`).
+    pub fn parse_row(s: &str) -> Result<u8, String> {
+        let s = s.trim();
+        let lower = s.to_lowercase();
+        let (digits, radix) = if let Some(d) = lower.strip_prefix("0d") {
+            (d, 10)
+        } else if let Some(h) = lower.strip_prefix("0x").or(lower.strip_prefix('$')) {
+            (h, 16)
+        } else {
+            (lower.as_str(), 16)
+        };
+        let invalid = || {
+            format!(
+                "Invalid row `{}`. Rows are hex from 00 to FF, as on the M8 (so 10 is row 16), \
+                 or decimal from 0d0 to 0d255",
+                s
+            )
+        };
+        if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+            return Err(invalid());
+        }
+        u8::from_str_radix(digits, radix).map_err(|_| invalid())
+    }
+}
+
+impl FromStr for RowRange {
+    type Err = String;
+
+    /// Parses a single starting row (`10`), or a range of rows with an exclusive (`10..20`)
+    /// or inclusive (`10..=1F`) end. Either side of a range can be left out to start from
+    /// the first row or play until the end.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().split_once("..") {
+            Some((start, end)) => {
+                let start = if start.trim().is_empty() {
+                    0
+                } else {
+                    Self::parse_row(start)?
+                };
+                let end = match end.strip_prefix('=') {
+                    // Row FF is the last row, so a range that includes it plays until the end
+                    Some(last) => Self::parse_row(last)?.checked_add(1),
+                    None if end.trim().is_empty() => None,
+                    None => Some(Self::parse_row(end)?),
+                };
+                Self::new(start, end)
+            }
+            None => Self::new(Self::parse_row(s)?, None),
+        }
+    }
+}
+
+impl fmt::Display for RowRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.end {
+            Some(end) => write!(f, "{:02X}..{:02X}", self.start, end),
+            None => write!(f, "{:02X}..", self.start),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::row_range::*;
+
+    #[test]
+    fn test_parse_row() {
+        assert_eq!(RowRange::parse_row("1F"), Ok(0x1F));
+        assert_eq!(RowRange::parse_row("ff"), Ok(0xFF));
+        assert_eq!(RowRange::parse_row("0x80"), Ok(0x80));
+        assert_eq!(RowRange::parse_row("$80"), Ok(0x80));
+        assert_eq!(RowRange::parse_row("0d255"), Ok(255));
+        // Bare digits are hex, like on the M8
+        assert_eq!(RowRange::parse_row("10"), Ok(0x10));
+        assert_eq!(RowRange::parse_row("99"), Ok(0x99));
+        assert_eq!(RowRange::parse_row("0d10"), Ok(10));
+        // A 0d prefix is decimal, even when the rest could be a hex row
+        assert_eq!(RowRange::parse_row("0d1"), Ok(1));
+        assert_eq!(RowRange::parse_row("d1"), Ok(0xD1));
+        assert!(RowRange::parse_row("0d1F").is_err());
+        assert!(RowRange::parse_row("0d256").is_err());
+        assert!(RowRange::parse_row("0x").is_err());
+        assert!(RowRange::parse_row("$").is_err());
+        assert!(RowRange::parse_row("-1").is_err());
+        assert!(RowRange::parse_row("").is_err());
+        // Decimal rows past FF get an error that explains rows are hex
+        assert_eq!(
+            RowRange::parse_row("100"),
+            Err(
+                "Invalid row `100`. Rows are hex from 00 to FF, as on the M8 (so 10 is row 16), \
+                 or decimal from 0d0 to 0d255"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!("90".parse(), RowRange::new(0x90, None));
+        assert_eq!("10..1F".parse(), RowRange::new(0x10, Some(0x1F)));
+        assert_eq!("0d16..$1F".parse(), RowRange::new(0x10, Some(0x1F)));
+        assert_eq!("10..=1F".parse(), RowRange::new(0x10, Some(0x20)));
+        assert_eq!("10..=10".parse(), RowRange::new(0x10, Some(0x11)));
+        assert_eq!("80..=FF".parse(), RowRange::new(0x80, None));
+        assert_eq!("..=0d255".parse(), RowRange::new(0, None));
+        assert_eq!("..20".parse(), RowRange::new(0, Some(0x20)));
+        assert_eq!("C0..".parse(), RowRange::new(0xC0, None));
+        assert!("1F..10".parse::<RowRange>().is_err());
+        assert!("10..10".parse::<RowRange>().is_err());
+        assert!("11..=10".parse::<RowRange>().is_err());
+        assert!("10..G0".parse::<RowRange>().is_err());
+        assert!("10..=".parse::<RowRange>().is_err());
+        assert_eq!(
+            RowRange::new(0x10, Some(0x1F)).unwrap().to_string(),
+            "10..1F"
+        );
+    }
+}
@@ -1,30 +1,299 @@
-use std::ops::Range;
+use std::collections::BTreeMap;
+use std::str::FromStr;
 
+use crate::fx_map::*;
 use crate::midi_file::*;
+use crate::report::*;
+use crate::row_range::RowRange;
+use crate::track_set::TrackSet;
 use m8_files::*;
 use midi_msg::*;
 
+/// Ticks per quarter note of the M8 sequencer
 pub const TICKS_PER_QUARTER_NOTE: u32 = 24;
 
+/// Convert M8 ticks to ticks at the given resolution (ticks per quarter note)
+pub fn scale_ticks(ticks: u32, ppq: u16) -> u32 {
+    (ticks as u64 * ppq as u64 / TICKS_PER_QUARTER_NOTE as u64).min(u32::MAX as u64) as u32
+}
+
+/// How notes are grouped into Midi tracks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TrackMode {
+    /// One Midi track per M8 song track
+    #[default]
+    Tracks,
+    /// One Midi track per M8 instrument, named after the instrument
+    Instruments,
+}
+
+/// The time signature written to the conductor track
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeSignature {
+    /// A fixed numerator and denominator, e.g. `Fixed(4, 4)`
+    Fixed(u8, u8),
+    /// One bar per phrase, its length depending on the song's first groove
+    Phrase,
+}
+
+impl TimeSignature {
+    /// The numerator and denominator of the time signature of a song
+    pub fn numerator_denominator(&self, song: &Song) -> (u8, u8) {
+        match *self {
+            Self::Fixed(numerator, denominator) => (numerator, denominator),
+            Self::Phrase => {
+                let steps = song
+                    .grooves
+                    .first()
+                    .map(|g| g.active_steps())
+                    .unwrap_or_default();
+                let phrase_ticks: u32 = if steps.is_empty() {
+                    TICKS_PER_QUARTER_NOTE * 4
+                } else {
+                    (0..16).map(|i| steps[i % steps.len()] as u32).sum()
+                };
+                // Find the largest note value that evenly divides the phrase
+                let whole_note = TICKS_PER_QUARTER_NOTE * 4;
+                [4, 8, 16, 32]
+                    .into_iter()
+                    .map(|denominator| (phrase_ticks * denominator, denominator))
+                    .find(|(ticks, _)| ticks % whole_note == 0)
+                    .map(|(ticks, denominator)| (ticks / whole_note, denominator))
+                    .filter(|(numerator, _)| (1..=0xFF).contains(numerator))
+                    .map(|(numerator, denominator)| (numerator as u8, denominator as u8))
+                    .unwrap_or((4, 4))
+            }
+        }
+    }
+}
+
+impl Default for TimeSignature {
+    fn default() -> Self {
+        Self::Fixed(4, 4)
+    }
+}
+
+impl FromStr for TimeSignature {
+    type Err = String;
+
+    /// Parses `<numerator>/<denominator>` or `phrase`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.eq_ignore_ascii_case("phrase") {
+            return Ok(Self::Phrase);
+        }
+        s.split_once('/')
+            .and_then(|(n, d)| Some((n.trim().parse::<u8>().ok()?, d.trim().parse::<u8>().ok()?)))
+            .filter(|(n, d)| *n > 0 && d.is_power_of_two())
+            .map(|(n, d)| Self::Fixed(n, d))
+            .ok_or_else(|| {
+                format!(
+                    "Invalid time signature `{}`. Expected e.g. 4/4, 7/8 or phrase",
+                    s
+                )
+            })
+    }
+}
+
+/// How M8 velocities (00-7F) are turned into Midi velocities (1-127)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VelocityCurve {
+    /// Velocities are used as-is
+    #[default]
+    Linear,
+    /// Quiet velocities are boosted
+    Soft,
+    /// Quiet velocities are made quieter
+    Hard,
+    /// Every note has the given velocity
+    Fixed(u8),
+}
+
+impl VelocityCurve {
+    /// The Midi velocity of an M8 velocity. Never 0, which would be read as a note off.
+    pub fn apply(&self, velocity: u8) -> u8 {
+        let x = velocity.min(0x7F) as f32 / 127.0;
+        let velocity = match *self {
+            Self::Linear => velocity,
+            Self::Soft => (x.sqrt() * 127.0).round() as u8,
+            Self::Hard => (x * x * 127.0).round() as u8,
+            Self::Fixed(velocity) => velocity,
+        };
+        velocity.clamp(1, 0x7F)
+    }
+}
+
+impl FromStr for VelocityCurve {
+    type Err = String;
+
+    /// Parses `linear`, `soft`, `hard` or a fixed velocity (1-127)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "linear" => Ok(Self::Linear),
+            "soft" => Ok(Self::Soft),
+            "hard" => Ok(Self::Hard),
+            v => v
+                .parse::<u8>()
+                .ok()
+                .filter(|v| (1..=0x7F).contains(v))
+                .map(Self::Fixed)
+                .ok_or_else(|| {
+                    format!(
+                        "Invalid velocity curve `{}`. Expected linear, soft, hard or 1-127",
+                        s
+                    )
+                }),
+        }
+    }
+}
+
+/// What happens to notes that are transposed outside of the Midi note range (0-127)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutOfRangeNotes {
+    /// Notes are moved to the nearest valid note
+    #[default]
+    Clamp,
+    /// Notes are moved by octaves until they are in range
+    Fold,
+    /// Notes are left out, with a warning
+    Drop,
+}
+
+impl OutOfRangeNotes {
+    /// The Midi note of a transposed note, if it is output
+    pub fn apply(&self, note: i16) -> Option<u8> {
+        if (0..=0x7F).contains(&note) {
+            return Some(note as u8);
+        }
+        match *self {
+            Self::Clamp => Some(note.clamp(0, 0x7F) as u8),
+            Self::Fold if note < 0 => Some(note.rem_euclid(12) as u8),
+            Self::Fold => Some((note - (note - 0x7F + 11) / 12 * 12) as u8),
+            Self::Drop => None,
+        }
+    }
+}
+
+impl FromStr for OutOfRangeNotes {
+    type Err = String;
+
+    /// Parses `clamp`, `fold` or `drop`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "clamp" => Ok(Self::Clamp),
+            "fold" => Ok(Self::Fold),
+            "drop" => Ok(Self::Drop),
+            _ => Err(format!(
+                "Invalid out of range note handling `{}`. Expected clamp, fold or drop",
+                s
+            )),
+        }
+    }
+}
+
+/// How ARP commands are output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Arpeggios {
+    /// Only the played note is output, and ARP commands are reported as unsupported
+    #[default]
+    Off,
+    /// The notes of the arpeggio are played at once, as a chord
+    Chord,
+    /// The notes of the arpeggio are played one after the other, changing every given
+    /// number of M8 ticks
+    Arpeggiate(u8),
+}
+
+impl FromStr for Arpeggios {
+    type Err = String;
+
+    /// Parses `off`, `chord`, or the number of ticks between the notes of an arpeggio (1-255)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "off" => Ok(Self::Off),
+            "chord" => Ok(Self::Chord),
+            ticks => ticks
+                .parse::<u8>()
+                .ok()
+                .filter(|t| *t > 0)
+                .map(Self::Arpeggiate)
+                .ok_or_else(|| {
+                    format!(
+                        "Invalid arpeggio setting `{}`. Expected off, chord or a number of ticks from 1-255",
+                        s
+                    )
+                }),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Config {
+    /// Offset from M8 notes to Midi notes, applied on top of the song, chain and table transpose
     pub global_transpose: i16,
+    /// How notes that end up outside of the Midi note range are handled
+    pub out_of_range_notes: OutOfRangeNotes,
+    /// How ARP commands are output
+    pub arpeggios: Arpeggios,
+    /// Maximum note length of each track, in ticks at `ppq`
     pub max_note_length: [u32; 8],
-    pub tracks: Range<usize>,
-    pub start_from: u8,
+    /// Song tracks to render
+    pub tracks: TrackSet,
+    /// Song rows to render
+    pub rows: RowRange,
+    /// Midi channel of each track
+    pub channels: [Channel; 8],
+    /// Use the channel of each step's instrument rather than that of the track.
+    /// MIDI OUT instruments use their own channel setting.
+    pub instrument_channels: bool,
+    pub track_mode: TrackMode,
+    /// FX commands to output as CC, pitch bend and aftertouch
+    pub fx_map: FxMap,
+    /// Output a program change whenever the instrument changes. MIDI OUT instruments use
+    /// their program setting, otherwise the instrument number is used.
+    pub program_changes: bool,
+    /// Precede program changes with a bank select (CC 0 and 32) for MIDI OUT instruments
+    /// that have a bank set
+    pub bank_select: bool,
+    pub time_signature: TimeSignature,
+    pub velocity_curve: VelocityCurve,
+    /// Resolution of the Midi file, in ticks per quarter note
+    pub ppq: u16,
+    /// How many times each track plays through the song. Like on the M8, a track that reaches
+    /// an empty row loops back to the first row of the block of rows it was playing.
+    pub repetitions: u32,
+    /// Keep looping tracks that are shorter than the longest track until it ends,
+    /// so that tracks of different lengths line up
+    pub loop_until_longest: bool,
 }
 impl Config {
     pub fn max_note_len(mut self, len_quarter: f32) -> Self {
-        let len = (len_quarter * TICKS_PER_QUARTER_NOTE as f32) as u32;
+        let len = (len_quarter * self.ppq as f32) as u32;
         self.max_note_length = [len, len, len, len, len, len, len, len];
         self
     }
+
+    /// Put each output track on its own channel, so that they can be told apart once merged
+    /// into a single track: Song track N on channel N, or in instrument mode, each instrument
+    /// on its own channel.
+    pub fn separate_channels(mut self) -> Self {
+        match self.track_mode {
+            TrackMode::Tracks => {
+                self.channels = std::array::from_fn(|i| Channel::from_u8(i as u8));
+                self.instrument_channels = false;
+            }
+            TrackMode::Instruments => self.instrument_channels = true,
+        }
+        self
+    }
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             global_transpose: 36,
+            out_of_range_notes: OutOfRangeNotes::default(),
+            arpeggios: Arpeggios::default(),
             max_note_length: [
                 std::u32::MAX / 2,
                 std::u32::MAX / 2,
@@ -35,144 +304,2698 @@ impl Default for Config {
                 std::u32::MAX / 2,
                 std::u32::MAX / 2,
             ],
-            tracks: 1..9,
-            start_from: 0,
+            tracks: TrackSet::all(),
+            rows: RowRange::default(),
+            channels: [Channel::Ch1; 8],
+            instrument_channels: false,
+            track_mode: TrackMode::Tracks,
+            fx_map: FxMap::default(),
+            program_changes: false,
+            bank_select: false,
+            time_signature: TimeSignature::default(),
+            velocity_curve: VelocityCurve::default(),
+            ppq: TICKS_PER_QUARTER_NOTE as u16,
+            repetitions: 1,
+            loop_until_longest: false,
         }
     }
 }
 
+/// The M8 sequencer commands that affect the rendered Midi
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FxCommand {
+    /// GRV: Change the groove
+    Groove(u8),
+    /// TPO: Change the tempo, in BPM
+    Tempo(u8),
+    /// TBL/TBX: Play the given table
+    Table(u8),
+    /// TIC: Change the number of ticks per table row
+    TableTick(u8),
+    /// DEL: Delay the step's note by a number of ticks
+    Delay(u8),
+    /// RET: Retrigger the note every `ticks` until the end of the step,
+    /// changing its velocity by `velocity` each time
+    Retrigger { ticks: u8, velocity: i16 },
+    /// KIL: Cut the note after a number of ticks
+    Kill(u8),
+    /// OFF (from firmware 3): Release the note after a number of ticks. Like on OFF steps,
+    /// the note ends there.
+    Off(u8),
+    /// HOP: In a phrase, move on to the given step of the next phrase, or stop the track if FF.
+    /// In a table, jump to the given row, or stop the table from 10 up.
+    Hop(u8),
+    /// REP: Play the last note again
+    Repeat,
+    /// ARP: Play the note along with the notes X and Y semitones above it
+    Arpeggio(u8),
+}
+
+impl FxCommand {
+    /// Whether the command does anything when it is in a table row
+    fn in_tables(&self) -> bool {
+        matches!(self, Self::TableTick(_) | Self::Hop(_))
+    }
+
+    fn from_fx(fx: &FX, song: &Song) -> Option<Self> {
+        // Commands that a version of the M8 doesn't have compare equal to an empty command
+        if fx.command == 0xFF {
+            return None;
+        }
+        let cmd = |name| fx.command_eq(name, song.version);
+        let value = fx.value;
+        if cmd("GRV") {
+            Some(Self::Groove(value))
+        } else if cmd("TPO") {
+            Some(Self::Tempo(value))
+        } else if cmd("TBL") || cmd("TBX") {
+            Some(Self::Table(value))
+        } else if cmd("TIC") {
+            Some(Self::TableTick(value))
+        } else if cmd("DEL") {
+            Some(Self::Delay(value))
+        } else if cmd("RET") {
+            // The high nibble is a signed velocity change, in increments of 8
+            let change = (value >> 4) as i16;
+            let change = if change > 7 { change - 16 } else { change };
+            Some(Self::Retrigger {
+                ticks: value & 0x0F,
+                velocity: change * 8,
+            })
+        } else if cmd("KIL") {
+            Some(Self::Kill(value))
+        } else if cmd("OFF") {
+            Some(Self::Off(value))
+        } else if cmd("HOP") {
+            Some(Self::Hop(value))
+        } else if cmd("REP") {
+            Some(Self::Repeat)
+        } else if cmd("ARP") {
+            Some(Self::Arpeggio(value))
+        } else {
+            None
+        }
+    }
+}
+
+/// The commands out of a step's three FX columns
+fn fx_commands(fx: [&FX; 3], song: &Song) -> Vec<FxCommand> {
+    fx.into_iter()
+        .filter_map(|fx| FxCommand::from_fx(fx, song))
+        .collect()
+}
+
+/// Where playback continues after a phrase
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Flow {
+    /// Continue with the next phrase, from the given step
+    Continue(usize),
+    /// Stop playing the track
+    Stop,
+}
+
+/// State of the arpeggio or chord of the current note
+#[derive(Debug)]
+struct ArpCtx {
+    /// Semitones above the played note of each note, starting with 0 for the played note
+    offsets: Vec<u8>,
+    /// Ticks between the notes of an arpeggio. Chords have none.
+    ticks: Option<u32>,
+    /// Index of the offset being played
+    position: usize,
+    /// Tick at which the next note of the arpeggio plays
+    next_tick: u32,
+    /// Tick at which the arpeggio started
+    start_tick: u32,
+    /// Velocity of the last note played
+    velocity: u8,
+}
+
+impl ArpCtx {
+    /// The notes of an ARP XY command: The played note, then the notes X and Y semitones
+    /// above it. Offsets of 0 are left out, so ARP 00 gives `None`.
+    fn new(value: u8, arpeggios: Arpeggios) -> Option<Self> {
+        let ticks = match arpeggios {
+            Arpeggios::Off => return None,
+            Arpeggios::Chord => None,
+            Arpeggios::Arpeggiate(ticks) => Some(ticks as u32),
+        };
+        let mut offsets = vec![0];
+        offsets.extend([value >> 4, value & 0x0F].into_iter().filter(|o| *o > 0));
+        if ticks.is_none() {
+            offsets.dedup();
+        }
+        (offsets.len() > 1).then_some(Self {
+            offsets,
+            ticks,
+            position: 0,
+            next_tick: 0,
+            start_tick: 0,
+            velocity: 0,
+        })
+    }
+
+    /// Start again from the played note
+    fn restart(&mut self, at_tick: u32) {
+        self.position = 0;
+        self.start_tick = at_tick;
+        self.next_tick = at_tick + self.ticks.unwrap_or(0);
+    }
+
+    /// Move on to the next note of the arpeggio
+    fn advance(&mut self) {
+        self.position = (self.position + 1) % self.offsets.len();
+        self.next_tick += self.ticks.unwrap_or(0);
+    }
+}
+
+/// State of the table that is playing alongside the current note
+#[derive(Debug)]
+struct TableCtx {
+    table: u8,
+    row: usize,
+    /// Ticks per table row
+    tick_rate: u32,
+    /// Tick at which the next row plays
+    next_tick: u32,
+    /// Tick at which the table started playing
+    start_tick: u32,
+    /// M8 note and velocity that triggered the table
+    note: u8,
+    velocity: u8,
+    /// Whether a HOP command has stopped the table
+    stopped: bool,
+}
+
+impl TableCtx {
+    fn new(table: u8, tick_rate: u8, at_tick: u32, note: u8, velocity: u8) -> Self {
+        Self {
+            table,
+            row: 0,
+            tick_rate: (tick_rate as u32).max(1),
+            next_tick: at_tick,
+            start_tick: at_tick,
+            note,
+            velocity,
+            stopped: false,
+        }
+    }
+
+    /// Read the current row, moving on to the next one.
+    /// Returns the M8 note and velocity of the row. The note can be out of range,
+    /// which is handled once it is transposed.
+    fn next_row(&mut self, song: &Song) -> (i16, u8) {
+        let step = &song.tables[self.table as usize].steps[self.row];
+        let mut next_row = (self.row + 1) % 16;
+        for command in fx_commands([&step.fx1, &step.fx2, &step.fx3], song) {
+            match command {
+                FxCommand::TableTick(ticks) => self.tick_rate = (ticks as u32).max(1),
+                FxCommand::Hop(row) if row >= 0x10 => self.stopped = true,
+                FxCommand::Hop(row) => next_row = row as usize,
+                _ => (),
+            }
+        }
+        self.row = next_row;
+        self.next_tick += self.tick_rate;
+
+        let note = self.note as i16 + (step.transpose as i8) as i16;
+        let velocity = if step.velocity != 0xFF {
+            step.velocity.min(0x7F)
+        } else {
+            self.velocity
+        };
+        (note, velocity)
+    }
+}
+
+fn instrument_name(instrument: &Instrument) -> &str {
+    match instrument {
+        Instrument::WavSynth(i) => &i.name,
+        Instrument::MacroSynth(i) => &i.name,
+        Instrument::Sampler(i) => &i.name,
+        Instrument::MIDIOut(i) => &i.name,
+        Instrument::FMSynth(i) => &i.name,
+        Instrument::HyperSynth(i) => &i.name,
+        Instrument::External(i) => &i.name,
+        Instrument::None => "",
+    }
+}
+
+/// The velocity of notes that don't set one, based on the volume of the instrument
+fn instrument_default_velocity(instrument: &Instrument) -> u8 {
+    match instrument {
+        Instrument::WavSynth(i) => i.synth_params.volume >> 1,
+        Instrument::MacroSynth(i) => i.synth_params.volume >> 1,
+        Instrument::Sampler(i) => i.synth_params.volume >> 1,
+        Instrument::FMSynth(i) => i.synth_params.volume >> 1,
+        Instrument::HyperSynth(i) => i.synth_params.volume >> 1,
+        Instrument::External(i) => i.synth_params.volume >> 1,
+        // MIDI OUT has no volume setting
+        Instrument::MIDIOut(_) | Instrument::None => 0x7F,
+    }
+}
+
+/// Whether the song and chain transpose apply to the notes of an instrument
+fn instrument_transposes(instrument: &Instrument) -> bool {
+    match instrument {
+        Instrument::WavSynth(i) => i.transpose,
+        Instrument::MacroSynth(i) => i.transpose,
+        Instrument::Sampler(i) => i.transpose,
+        Instrument::MIDIOut(i) => i.transpose,
+        Instrument::FMSynth(i) => i.transpose,
+        Instrument::HyperSynth(i) => i.transpose,
+        Instrument::External(i) => i.transpose,
+        Instrument::None => true,
+    }
+}
+
+fn instrument_table_tick(instrument: &Instrument) -> u8 {
+    match instrument {
+        Instrument::WavSynth(i) => i.table_tick,
+        Instrument::MacroSynth(i) => i.table_tick,
+        Instrument::Sampler(i) => i.table_tick,
+        Instrument::MIDIOut(i) => i.table_tick,
+        Instrument::FMSynth(i) => i.table_tick,
+        Instrument::HyperSynth(i) => i.table_tick,
+        Instrument::External(i) => i.table_tick,
+        Instrument::None => 1,
+    }
+}
+
+/// Steps with a note from this value up to FE are note offs (OFF). Empty steps hold FF.
+const NOTE_OFF: u8 = 0x80;
+
+/// Names of the M8's sequencer and mixer commands before firmware 3, by command number
+const SEQUENCER_COMMANDS_V2: [&str; 0x3B] = [
+    "ARP", "CHA", "DEL", "GRV", "HOP", "KIL", "RAN", "RET", "REP", "NTH", "PSL", "PSN", "PVB",
+    "PVX", "SCA", "SCG", "SED", "SNG", "TBL", "THO", "TIC", "TPO", "TSP", "VMV", "XCM", "XCF",
+    "XCW", "XCR", "XDT", "XDF", "XDW", "XDR", "XRS", "XRD", "XRM", "XRF", "XRW", "XRZ", "VCH",
+    "VCD", "VRE", "VT1", "VT2", "VT3", "VT4", "VT5", "VT6", "VT7", "VT8", "DJF", "IVO", "ICH",
+    "IDE", "IRE", "IV2", "IC2", "ID2", "IR2", "USB",
+];
+
+/// Names of the M8's sequencer and mixer commands from firmware 3, by command number
+const SEQUENCER_COMMANDS_V3: [&str; 0x3F] = [
+    "ARP", "CHA", "DEL", "GRV", "HOP", "KIL", "RND", "RNL", "RET", "REP", "RMX", "NTH", "PSL",
+    "PBN", "PVB", "PVX", "SCA", "SCG", "SED", "SNG", "TBL", "THO", "TIC", "TBX", "TPO", "TSP",
+    "OFF", "VMV", "XCM", "XCF", "XCW", "XCR", "XDT", "XDF", "XDW", "XDR", "XRS", "XRD", "XRM",
+    "XRF", "XRW", "XRZ", "VCH", "VCD", "VRE", "VT1", "VT2", "VT3", "VT4", "VT5", "VT6", "VT7",
+    "VT8", "DJF", "IVO", "ICH", "IDE", "IRE", "IV2", "IC2", "ID2", "IR2", "USB",
+];
+
+/// The sequencer and mixer command names of a song's firmware version, by command number
+fn sequencer_commands(version: Version) -> &'static [&'static str] {
+    if version.at_least(3, 0) {
+        &SEQUENCER_COMMANDS_V3
+    } else {
+        &SEQUENCER_COMMANDS_V2
+    }
+}
+
+/// The name of a sequencer or instrument command, or else the command number in hex
+fn fx_command_name(fx: &FX, song: &Song, instrument: Option<&Instrument>) -> String {
+    sequencer_commands(song.version)
+        .get(fx.command as usize)
+        .copied()
+        .or_else(|| instrument_command_name(fx, instrument))
+        .map(|name| name.to_string())
+        .unwrap_or_else(|| format!("{:02X}", fx.command))
+}
+
+/// The channel set on MIDI OUT instruments, otherwise derived from the instrument number
+fn instrument_channel(instrument_num: u8, song: &Song) -> Channel {
+    match song.instruments.get(instrument_num as usize) {
+        Some(Instrument::MIDIOut(i)) => Channel::from_u8(i.channel),
+        _ => Channel::from_u8(instrument_num % 16),
+    }
+}
+
+/// A note that is sounding
+#[derive(Debug, Clone, Copy)]
+struct Voice {
+    /// Midi note
+    note: u8,
+    channel: Channel,
+    /// Instrument that played the note
+    instrument: u8,
+    /// M8 tick at which the note started
+    start_tick: u32,
+    /// Where the note was played
+    location: Location,
+}
+
 #[derive(Debug)]
 struct TrackCtx {
     /// Ticks elapsed
     ticks: u32,
-    transpose: i16,
+    /// Transpose of the song and of the current chain step. Instruments can opt out of these.
+    song_transpose: i16,
+    chain_transpose: i16,
     global_transpose: i16,
+    out_of_range_notes: OutOfRangeNotes,
+    /// Where the current step is, for the report
+    chain_num: u8,
+    phrase_num: u8,
+    step_num: usize,
+    report: TrackReport,
+    /// In ticks at `ppq`
     max_note_length: u32,
+    /// Ticks per quarter note of the Midi output. Ticks are counted in M8 ticks
+    /// and scaled when events are output.
+    ppq: u16,
     groove: Groove,
-    last_note: u8,
-    last_note_tick: u32,
-    events: Vec<(u32, MidiMsg)>,
+    /// Channel of the track
+    track_channel: Channel,
+    /// Use the channel of the instrument instead of the track's
+    instrument_channels: bool,
+    /// Channel of the last triggered note
+    channel: Channel,
+    /// Notes that are currently sounding
+    voices: Vec<Voice>,
+    /// M8 note and velocity of the last triggered step
+    m8_note: u8,
+    velocity: u8,
+    /// M8 note that is playing, as changed by its table
+    current_note: i16,
+    /// Last velocity set in the velocity column of the track
+    last_velocity: Option<u8>,
+    velocity_curve: VelocityCurve,
+    /// Tick at which a KIL command cuts the current note
+    kill_tick: Option<u32>,
+    track_mode: TrackMode,
+    /// Last instrument played
+    instrument: u8,
+    /// Table that will be played by the next note
+    table_num: u8,
+    table: Option<TableCtx>,
+    arpeggios: Arpeggios,
+    /// Arpeggio or chord of the current note
+    arp: Option<ArpCtx>,
+    fx_map: FxMap,
+    program_changes: bool,
+    bank_select: bool,
+    /// Instrument of the last program change
+    program_instrument: Option<u8>,
+    events: Vec<(u32, MidiFileEvent)>,
+    /// Events of each instrument, when rendering in instrument mode
+    instrument_events: BTreeMap<u8, Vec<(u32, MidiFileEvent)>>,
+    /// Tempo changes (tick, BPM) from TPO commands
+    tempo_changes: Vec<(u32, f32)>,
+    /// Ticks at which each song row started
+    rows: Vec<(u32, usize)>,
+    /// Ticks at which each chain and phrase started, and their description
+    cues: Vec<(u32, String)>,
+    /// Tick at which the track is cut off
+    end_tick: Option<u32>,
 }
 
 impl TrackCtx {
+    fn new(track: usize, song: &Song, cfg: &Config, end_tick: Option<u32>) -> Self {
+        Self {
+            ticks: 0,
+            song_transpose: (song.transpose as i8) as i16,
+            chain_transpose: 0,
+            global_transpose: cfg.global_transpose,
+            out_of_range_notes: cfg.out_of_range_notes,
+            chain_num: 0xFF,
+            phrase_num: 0xFF,
+            step_num: 0,
+            report: TrackReport {
+                track: track + 1,
+                ..TrackReport::default()
+            },
+            max_note_length: cfg.max_note_length[track],
+            ppq: cfg.ppq,
+            groove: song.grooves[0].clone(),
+            track_channel: cfg.channels[track],
+            instrument_channels: cfg.instrument_channels,
+            channel: cfg.channels[track],
+            voices: vec![],
+            m8_note: 255,
+            velocity: 0,
+            current_note: 0,
+            last_velocity: None,
+            velocity_curve: cfg.velocity_curve,
+            kill_tick: None,
+            track_mode: cfg.track_mode,
+            instrument: 0,
+            table_num: 0,
+            table: None,
+            arpeggios: cfg.arpeggios,
+            arp: None,
+            fx_map: cfg.fx_map.clone(),
+            program_changes: cfg.program_changes,
+            bank_select: cfg.bank_select,
+            program_instrument: None,
+            events: vec![],
+            instrument_events: BTreeMap::new(),
+            tempo_changes: vec![],
+            rows: vec![],
+            cues: vec![],
+            end_tick,
+        }
+    }
+
     fn groove_ticks(&self, step: usize) -> u32 {
         let steps = &self.groove.active_steps();
         steps[step % steps.len()] as u32
     }
 
-    fn change_groove(&mut self, step: &Step, song: &Song) {
-        if step.fx1.command_eq("GRV", song.version) {
-            self.groove = song.grooves[step.fx1.value as usize].clone();
+    fn change_table(&mut self, table_num: u8, song: &Song) {
+        if (table_num as usize) >= song.tables.len() {
+            return;
+        }
+        self.table_num = table_num;
+        // Restart the table of the currently playing note
+        if let Some(table) = self.table.as_mut() {
+            table.table = table_num;
+            table.row = 0;
+            table.next_tick = self.ticks;
+        }
+    }
+
+    /// Play a phrase step, interpreting its commands.
+    /// Returns the value of any HOP command.
+    fn play_step(&mut self, step: &Step, step_num: usize, song: &Song) -> Option<u8> {
+        self.step_num = step_num;
+        let mut delay = 0;
+        let mut retrigger = None;
+        let mut kill = None;
+        let mut hop = None;
+        let mut repeat = false;
+        let mut arpeggio = None;
+        let mut tempo = None;
+        let mut table = None;
+        for command in fx_commands([&step.fx1, &step.fx2, &step.fx3], song) {
+            match command {
+                FxCommand::Groove(groove) => {
+                    if let Some(g) = song.grooves.get(groove as usize) {
+                        self.groove = g.clone();
+                    }
+                }
+                FxCommand::Tempo(bpm) if bpm > 0 => tempo = Some(bpm),
+                FxCommand::Table(t) => table = Some(t),
+                FxCommand::Delay(ticks) => delay = ticks as u32,
+                FxCommand::Retrigger { ticks, velocity } if ticks > 0 => {
+                    retrigger = Some((ticks as u32, velocity))
+                }
+                FxCommand::Kill(ticks) | FxCommand::Off(ticks) => kill = Some(ticks as u32),
+                FxCommand::Hop(value) => hop = Some(value),
+                FxCommand::Repeat => repeat = true,
+                FxCommand::Arpeggio(value) => arpeggio = Some(value),
+                _ => (),
+            }
+        }
+
+        let end = (self.ticks + self.groove_ticks(step_num)).min(self.end_tick.unwrap_or(u32::MAX));
+        let trigger_tick = (self.ticks + delay).min(end);
+        self.advance_to(trigger_tick, song);
+        if let Some(bpm) = tempo {
+            self.tempo_changes.push((trigger_tick, bpm as f32));
+        }
+
+        let note = Some(step.note.0).filter(|n| *n < NOTE_OFF);
+        if note.is_some() && step.instrument != 0xFF && step.instrument != self.instrument {
+            self.instrument = step.instrument;
+            self.table_num = step.instrument;
+        }
+        // TBL and TBX override the table of a new instrument
+        if let Some(table) = table {
+            self.change_table(table, song);
+        }
+        if note.is_some() {
+            self.add_program_change(trigger_tick, song);
+        }
+        self.add_fx_events(trigger_tick, [&step.fx1, &step.fx2, &step.fx3], false, song);
+
+        // A new note only keeps playing an arpeggio if its step has an ARP command
+        let had_arpeggio = self.arp.is_some();
+        if let Some(value) = arpeggio {
+            self.arp = ArpCtx::new(value, self.arpeggios);
+        } else if note.is_some() {
+            self.arp = None;
+        }
+
+        // The M8 releases the note on an OFF step. How long the instrument's release
+        // lasts isn't known, so the note ends here.
+        let note_off = step.note.0 != 0xFF && note.is_none();
+        if note_off {
+            self.end_note(trigger_tick);
+        }
+
+        let triggered = if let Some(note) = note {
+            self.kill_tick = None;
+            let velocity = self.step_velocity(step.velocity, song);
+            self.trigger_note(note, velocity, song);
+            true
+        } else if repeat && !note_off && self.m8_note != 255 {
+            let velocity = if step.velocity != 0xFF {
+                self.step_velocity(step.velocity, song)
+            } else {
+                self.velocity
+            };
+            self.kill_tick = None;
+            self.trigger_note(self.m8_note, velocity, song);
+            true
+        } else {
+            false
+        };
+
+        // An ARP command without a note replays the note that is playing with the new arpeggio
+        if !triggered
+            && arpeggio.is_some()
+            && (had_arpeggio || self.arp.is_some())
+            && !self.voices.is_empty()
+        {
+            self.end_voices(trigger_tick);
+            if let Some(arp) = self.arp.as_mut() {
+                arp.restart(trigger_tick);
+            }
+            self.play_note(trigger_tick, self.current_note, self.velocity, song);
+        }
+
+        if let Some(ticks) = kill {
+            self.kill_tick = Some(trigger_tick + ticks);
+        }
+
+        if let Some((ticks, velocity_change)) = retrigger.filter(|_| triggered) {
+            let mut at_tick = trigger_tick + ticks;
+            while at_tick < end {
+                self.advance_to(at_tick, song);
+                if self.voices.is_empty() {
+                    // The note has been cut
+                    break;
+                }
+                let velocity = (self.velocity as i16 + velocity_change).clamp(0, 0x7F) as u8;
+                self.trigger_note(self.m8_note, velocity, song);
+                at_tick += ticks;
+            }
+        }
+
+        self.advance_to(end, song);
+        hop
+    }
+
+    /// The M8 velocity of a step. An empty velocity column carries over the last velocity
+    /// set on the track, or else uses the default volume of the instrument.
+    fn step_velocity(&mut self, velocity: u8, song: &Song) -> u8 {
+        if velocity != 0xFF {
+            let velocity = velocity.min(0x7F);
+            self.last_velocity = Some(velocity);
+            velocity
+        } else {
+            self.last_velocity.unwrap_or_else(|| {
+                song.instruments
+                    .get(self.instrument as usize)
+                    .map(instrument_default_velocity)
+                    .unwrap_or(0x7F)
+            })
         }
-        if step.fx2.command_eq("GRV", song.version) {
-            self.groove = song.grooves[step.fx2.value as usize].clone();
+    }
+
+    /// Start a note and its table
+    fn trigger_note(&mut self, note: u8, velocity: u8, song: &Song) {
+        self.end_voices(self.ticks);
+        self.m8_note = note;
+        self.velocity = velocity;
+        self.channel = self.instrument_channel(song);
+        if let Some(arp) = self.arp.as_mut() {
+            arp.restart(self.ticks);
         }
-        if step.fx3.command_eq("GRV", song.version) {
-            self.groove = song.grooves[step.fx3.value as usize].clone();
+
+        let tick_rate = song
+            .instruments
+            .get(self.instrument as usize)
+            .map(instrument_table_tick)
+            .unwrap_or(1);
+        if (self.table_num as usize) < song.tables.len() {
+            let mut table = TableCtx::new(self.table_num, tick_rate, self.ticks, note, velocity);
+            // The first row of the table modifies the triggered note
+            let row = &song.tables[table.table as usize].steps[0];
+            self.add_fx_events(self.ticks, [&row.fx1, &row.fx2, &row.fx3], true, song);
+            let (note, velocity) = table.next_row(song);
+            self.current_note = note;
+            self.play_note(self.ticks, note, velocity, song);
+            self.table = Some(table).filter(|t| !t.stopped);
+        } else {
+            self.current_note = note as i16;
+            self.play_note(self.ticks, note as i16, velocity, song);
+            self.table = None;
         }
     }
 
-    fn add_note_off(&mut self, at_tick: u32, channel: Channel, note: u8) {
-        self.events.push((
-            at_tick.min(self.last_note_tick + self.max_note_length),
+    /// Play an M8 note along with the rest of its chord, or as the current note of its arpeggio
+    fn play_note(&mut self, at_tick: u32, note: i16, velocity: u8, song: &Song) {
+        let offsets = match self.arp.as_mut() {
+            Some(arp) => {
+                arp.velocity = velocity;
+                match arp.ticks {
+                    Some(_) => vec![arp.offsets[arp.position]],
+                    None => arp.offsets.clone(),
+                }
+            }
+            None => vec![0],
+        };
+        for offset in offsets {
+            self.add_note_on(at_tick, self.channel, note + offset as i16, velocity, song);
+        }
+    }
+
+    /// End the current note, its table and its arpeggio
+    fn end_note(&mut self, at_tick: u32) {
+        self.end_voices(at_tick);
+        self.table = None;
+        self.arp = None;
+        self.kill_tick = None;
+    }
+
+    /// Move forward to `end`, playing any table rows, arpeggio notes or note cuts that fall
+    /// before it
+    fn advance_to(&mut self, end: u32, song: &Song) {
+        if let Some(kill_tick) = self.kill_tick.filter(|t| *t <= end) {
+            self.play_arpeggio(kill_tick, song);
+            self.end_note(kill_tick);
+        }
+        self.play_arpeggio(end, song);
+        self.ticks = end;
+    }
+
+    /// Play the arpeggio notes that fall before `end`, along with the table rows before them
+    fn play_arpeggio(&mut self, end: u32, song: &Song) {
+        let max_ticks = self.max_note_m8_ticks();
+        while let Some(at_tick) = self.arp.as_ref().and_then(|arp| {
+            let arp_end = end.min(arp.start_tick.saturating_add(max_ticks));
+            arp.ticks.map(|_| arp.next_tick).filter(|t| *t < arp_end)
+        }) {
+            self.play_table(at_tick, song);
+            let Some(arp) = self.arp.as_mut() else {
+                break;
+            };
+            arp.advance();
+            let velocity = arp.velocity;
+            self.end_voices(at_tick);
+            self.play_note(at_tick, self.current_note, velocity, song);
+        }
+        self.play_table(end, song);
+    }
+
+    /// Play the table rows that fall before `end`
+    fn play_table(&mut self, end: u32, song: &Song) {
+        if let Some(mut table) = self.table.take() {
+            let table_end = end.min(table.start_tick.saturating_add(self.max_note_m8_ticks()));
+            while !table.stopped && table.next_tick < table_end {
+                let at_tick = table.next_tick;
+                let row = &song.tables[table.table as usize].steps[table.row];
+                let row_velocity = row.velocity;
+                self.add_fx_events(at_tick, [&row.fx1, &row.fx2, &row.fx3], true, song);
+                let (note, velocity) = table.next_row(song);
+                // Only changes in pitch or explicit velocities produce a new note
+                if note != self.current_note || row_velocity != 0xFF {
+                    self.end_voices(at_tick);
+                    self.play_note(at_tick, note, velocity, song);
+                    self.current_note = note;
+                }
+            }
+            self.table = Some(table).filter(|t| !t.stopped);
+        }
+    }
+
+    /// The channel of the current instrument
+    fn instrument_channel(&self, song: &Song) -> Channel {
+        if self.instrument_channels {
+            instrument_channel(self.instrument, song)
+        } else {
+            self.track_channel
+        }
+    }
+
+    /// Output a program change, and optionally a bank select, if the instrument has changed
+    fn add_program_change(&mut self, at_tick: u32, song: &Song) {
+        if !self.program_changes || self.program_instrument == Some(self.instrument) {
+            return;
+        }
+        self.program_instrument = Some(self.instrument);
+
+        let (program, bank) = match song.instruments.get(self.instrument as usize) {
+            Some(Instrument::MIDIOut(i)) => (i.program_change, i.bank_select),
+            _ => (self.instrument, 0xFF),
+        };
+        let channel = self.instrument_channel(song);
+        let mut msgs = vec![];
+        if self.bank_select && bank != 0xFF {
+            msgs.push(ChannelVoiceMsg::ControlChange {
+                control: midi_msg::ControlChange::Undefined {
+                    control: 0,
+                    value: bank & 0x7F,
+                },
+            });
+            msgs.push(ChannelVoiceMsg::ControlChange {
+                control: midi_msg::ControlChange::Undefined {
+                    control: 32,
+                    value: 0,
+                },
+            });
+        }
+        // Unset MIDI OUT programs don't send a program change
+        if program != 0xFF {
+            msgs.push(ChannelVoiceMsg::ProgramChange {
+                program: program & 0x7F,
+            });
+        }
+        for msg in msgs {
+            self.push_event(
+                at_tick,
+                self.instrument,
+                MidiMsg::ChannelVoice { channel, msg }.into(),
+            );
+        }
+    }
+
+    /// Output the Midi events that FX commands are mapped to.
+    /// `in_table` is whether the commands are from a table row.
+    fn add_fx_events(&mut self, at_tick: u32, fx: [&FX; 3], in_table: bool, song: &Song) {
+        self.report_unsupported_fx(fx, in_table, song);
+        let instrument = song.instruments.get(self.instrument as usize);
+        for fx in fx {
+            if let Some(msg) = self
+                .fx_map
+                .get(fx, song, instrument)
+                .and_then(|mapping| mapping.to_msg(fx.value, instrument))
+            {
+                let channel = self.instrument_channel(song);
+                self.push_event(
+                    at_tick,
+                    self.instrument,
+                    MidiMsg::ChannelVoice { channel, msg }.into(),
+                );
+            }
+        }
+    }
+
+    /// The max note length in M8 ticks, rounded up
+    fn max_note_m8_ticks(&self) -> u32 {
+        (self.max_note_length as u64 * TICKS_PER_QUARTER_NOTE as u64)
+            .div_ceil(self.ppq.max(1) as u64)
+            .min(u32::MAX as u64) as u32
+    }
+
+    /// Output an event at the given M8 tick
+    fn push_event(&mut self, at_tick: u32, instrument: u8, event: MidiFileEvent) {
+        self.push_midi_event(scale_ticks(at_tick, self.ppq), instrument, event);
+    }
+
+    /// Output an event at the given tick of the Midi file
+    fn push_midi_event(&mut self, at_tick: u32, instrument: u8, event: MidiFileEvent) {
+        self.events_mut(instrument).push((at_tick, event));
+    }
+
+    /// The events that the events of an instrument are output to
+    fn events_mut(&mut self, instrument: u8) -> &mut Vec<(u32, MidiFileEvent)> {
+        match self.track_mode {
+            TrackMode::Tracks => &mut self.events,
+            TrackMode::Instruments => self.instrument_events.entry(instrument).or_default(),
+        }
+    }
+
+    /// End every sounding note
+    fn end_voices(&mut self, at_tick: u32) {
+        for voice in std::mem::take(&mut self.voices) {
+            self.add_note_off(at_tick, voice);
+        }
+    }
+
+    /// End a note, no later than the max note length after it started.
+    /// A note that would end on the tick it started can't be heard, and its note off
+    /// would be sorted before its note on, so it is removed instead.
+    fn add_note_off(&mut self, at_tick: u32, voice: Voice) {
+        let start_tick = scale_ticks(voice.start_tick, self.ppq);
+        let max_tick = start_tick.saturating_add(self.max_note_length);
+        let at_tick = scale_ticks(at_tick, self.ppq);
+        if at_tick > max_tick {
+            self.report.truncated_notes.push(TruncatedNote {
+                location: voice.location,
+                note: voice.note,
+            });
+        }
+        let at_tick = at_tick.min(max_tick);
+        if at_tick <= start_tick {
+            let events = self.events_mut(voice.instrument);
+            if let Some(i) = events.iter().rposition(|(tick, e)| {
+                *tick == start_tick
+                    && matches!(e, MidiFileEvent::Midi(MidiMsg::ChannelVoice {
+                        channel,
+                        msg: ChannelVoiceMsg::NoteOn { note, .. },
+                    }) if *channel == voice.channel && *note == voice.note)
+            }) {
+                events.remove(i);
+                self.report.notes -= 1;
+                return;
+            }
+        }
+        self.push_midi_event(
+            at_tick,
+            voice.instrument,
             MidiMsg::ChannelVoice {
-                channel,
-                msg: ChannelVoiceMsg::NoteOff { note, velocity: 0 },
-            },
-        ));
+                channel: voice.channel,
+                msg: ChannelVoiceMsg::NoteOff {
+                    note: voice.note,
+                    velocity: 0,
+                },
+            }
+            .into(),
+        );
+    }
+
+    /// Where the current step is in the song
+    fn location(&self) -> Location {
+        Location {
+            row: self.rows.last().map(|(_, row)| *row as u8),
+            chain: Some(self.chain_num).filter(|c| *c != 0xFF),
+            phrase: self.phrase_num,
+            step: self.step_num as u8,
+        }
+    }
+
+    /// Note FX commands that don't affect the output, once per step they are found on.
+    /// Tables only play TIC and HOP commands.
+    fn report_unsupported_fx(&mut self, fx: [&FX; 3], in_table: bool, song: &Song) {
+        let instrument = song.instruments.get(self.instrument as usize);
+        for fx in fx {
+            let supported = match FxCommand::from_fx(fx, song) {
+                Some(command) if in_table => command.in_tables(),
+                Some(FxCommand::Arpeggio(_)) => self.arpeggios != Arpeggios::Off,
+                Some(_) => true,
+                None => self.fx_map.get(fx, song, instrument).is_some(),
+            };
+            if fx.command == 0xFF || supported {
+                continue;
+            }
+            let unsupported = UnsupportedFx {
+                location: self.location(),
+                command: fx_command_name(fx, song, instrument),
+                value: fx.value,
+            };
+            if !self.report.unsupported_fx.contains(&unsupported) {
+                self.report.unsupported_fx.push(unsupported);
+            }
+        }
+    }
+
+    /// The Midi note of an M8 note, transposed by the song, chain and `global_transpose`
+    fn midi_note(&mut self, note: i16, song: &Song) -> Option<u8> {
+        let mut transposed = note + self.global_transpose;
+        if song
+            .instruments
+            .get(self.instrument as usize)
+            .map(instrument_transposes)
+            .unwrap_or(true)
+        {
+            transposed += self.song_transpose + self.chain_transpose;
+        }
+        let midi_note = self.out_of_range_notes.apply(transposed);
+        if !(0..=0x7F).contains(&transposed) {
+            self.report.out_of_range_notes.push(OutOfRangeNote {
+                location: self.location(),
+                note: transposed,
+                output: midi_note,
+            });
+        }
+        midi_note
     }
 
-    fn add_note_on(&mut self, at_tick: u32, channel: Channel, note: u8, velocity: u8) {
-        let actual_note = (note as i16 + self.transpose + self.global_transpose) as u8;
-        self.last_note_tick = at_tick;
-        self.last_note = actual_note;
-        self.events.push((
+    /// Start a note, alongside any that are already sounding.
+    /// A sounding note of the same pitch and channel is ended first.
+    fn add_note_on(
+        &mut self,
+        at_tick: u32,
+        channel: Channel,
+        note: i16,
+        velocity: u8,
+        song: &Song,
+    ) {
+        let Some(actual_note) = self.midi_note(note, song) else {
+            return;
+        };
+        if let Some(i) = self
+            .voices
+            .iter()
+            .position(|v| v.note == actual_note && v.channel == channel)
+        {
+            let voice = self.voices.remove(i);
+            self.add_note_off(at_tick, voice);
+        }
+        self.voices.push(Voice {
+            note: actual_note,
+            channel,
+            instrument: self.instrument,
+            start_tick: at_tick,
+            location: self.location(),
+        });
+        self.report.notes += 1;
+        self.push_event(
             at_tick,
+            self.instrument,
             MidiMsg::ChannelVoice {
                 channel,
                 msg: ChannelVoiceMsg::NoteOn {
                     note: actual_note,
-                    velocity,
+                    velocity: self.velocity_curve.apply(velocity),
                 },
-            },
-        ));
+            }
+            .into(),
+        );
     }
 }
 
 pub fn song_to_midi_file(song: &Song, cfg: &Config) -> MidiFile {
-    MidiFile {
+    song_to_midi_file_with_report(song, cfg).0
+}
+
+/// Render a song, along with a report of what was output and of anything that couldn't be
+pub fn song_to_midi_file_with_report(song: &Song, cfg: &Config) -> (MidiFile, ConversionReport) {
+    let (tracks, report) = song_to_tracks(song, cfg);
+    let midi_file = MidiFile {
         format: MidiFileFormat::SimultaniousTracks,
-        ticks_per_quarter_note: TICKS_PER_QUARTER_NOTE as u16,
-        tracks: song_to_tracks(song, cfg),
-    }
+        ticks_per_quarter_note: cfg.ppq,
+        tracks,
+    };
+    (midi_file, report)
 }
 
-pub fn song_to_midi(song: &Song, cfg: &Config) -> Vec<u8> {
+pub fn song_to_midi(song: &Song, cfg: &Config) -> Result<Vec<u8>, crate::midi_file::Error> {
     let f = song_to_midi_file(song, cfg);
     // dbg!(&f);
     f.to_midi()
 }
 
-fn song_to_tracks(song: &Song, cfg: &Config) -> Vec<MidiFileTrack> {
-    cfg.tracks
-        .clone()
-        .map(|x| collect_track_events(x - 1, song, cfg))
-        .collect()
+fn song_to_tracks(song: &Song, cfg: &Config) -> (Vec<MidiFileTrack>, ConversionReport) {
+    // Every track is rendered so that TPO commands are picked up even from
+    // tracks that aren't being output
+    let mut ctxs: Vec<TrackCtx> = (0..8)
+        .map(|track| collect_track_events(track, song, cfg, None))
+        .collect();
+    if cfg.loop_until_longest {
+        let longest = ctxs
+            .iter()
+            .enumerate()
+            .filter(|(track, _)| cfg.tracks.contains(track + 1))
+            .map(|(_, ctx)| ctx.ticks)
+            .max()
+            .unwrap_or(0);
+        // Tracks that don't play anything have nothing to loop until
+        if longest > 0 {
+            ctxs = (0..8)
+                .map(|track| collect_track_events(track, song, cfg, Some(longest)))
+                .collect();
+        }
+    }
+
+    let mut tempo_changes = vec![(0, song.tempo)];
+    let mut track_ctxs = vec![];
+    let mut report = ConversionReport::default();
+    let (numerator, denominator) = cfg.time_signature.numerator_denominator(song);
+    let bar_ticks =
+        (numerator as u32 * TICKS_PER_QUARTER_NOTE * 4 / (denominator as u32).max(1)).max(1);
+    for (track, mut ctx) in ctxs.into_iter().enumerate() {
+        tempo_changes.append(&mut ctx.tempo_changes);
+        if cfg.tracks.contains(track + 1) {
+            let mut track_report = std::mem::take(&mut ctx.report);
+            track_report.bars = ctx.ticks as f32 / bar_ticks as f32;
+            report.tracks.push(track_report);
+            track_ctxs.push((track, ctx));
+        }
+    }
+    (ctxs_to_tracks(song, cfg, tempo_changes, track_ctxs), report)
+}
+
+/// Render a single chain as a clip, played `cfg.repetitions` times, using the settings
+/// of the first track of `cfg.tracks`. Returns `None` if the chain doesn't exist.
+pub fn chain_to_midi_file(song: &Song, chain: u8, cfg: &Config) -> Option<MidiFile> {
+    song.chains.get(chain as usize)?;
+    Some(clip_to_midi_file(
+        song,
+        cfg,
+        &format!("Chain {:02X}", chain),
+        |start_step, ctx| collect_chain_events(chain, start_step, song, ctx),
+    ))
+}
+
+/// Render a single phrase as a clip, played `cfg.repetitions` times, using the settings
+/// of the first track of `cfg.tracks`. Returns `None` if the phrase doesn't exist.
+pub fn phrase_to_midi_file(song: &Song, phrase: u8, cfg: &Config) -> Option<MidiFile> {
+    song.phrases.get(phrase as usize)?;
+    Some(clip_to_midi_file(
+        song,
+        cfg,
+        &format!("Phrase {:02X}", phrase),
+        |start_step, ctx| collect_phrase_events(phrase, start_step, song, ctx),
+    ))
+}
+
+fn clip_to_midi_file(
+    song: &Song,
+    cfg: &Config,
+    name: &str,
+    mut play: impl FnMut(usize, &mut TrackCtx) -> Flow,
+) -> MidiFile {
+    let track = cfg.tracks.first().unwrap_or(1) - 1;
+    let mut ctx = TrackCtx::new(track, song, cfg, None);
+    let mut start_step = 0;
+    for _ in 0..cfg.repetitions.max(1) {
+        match play(start_step, &mut ctx) {
+            Flow::Continue(step) => start_step = step,
+            Flow::Stop => break,
+        }
+    }
+    ctx.end_note(ctx.ticks);
+
+    let mut tempo_changes = vec![(0, song.tempo)];
+    tempo_changes.append(&mut ctx.tempo_changes);
+    let mut tracks = ctxs_to_tracks(song, cfg, tempo_changes, vec![(track, ctx)]);
+    if cfg.track_mode == TrackMode::Tracks {
+        tracks[1].name = Some(format!("{}_{}", song.name, name));
+    }
+    MidiFile {
+        format: MidiFileFormat::SimultaniousTracks,
+        ticks_per_quarter_note: cfg.ppq,
+        tracks,
+    }
 }
 
-fn collect_track_events(track: usize, song: &Song, cfg: &Config) -> MidiFileTrack {
-    let mut ctx = TrackCtx {
-        ticks: 0,
-        transpose: 0,
-        global_transpose: cfg.global_transpose,
-        max_note_length: cfg.max_note_length[track],
-        groove: song.grooves[0].clone(),
-        last_note: 255,
-        last_note_tick: 0,
-        events: vec![],
+/// The conductor track followed by the tracks of each rendered song track, or of each
+/// instrument in instrument mode
+fn ctxs_to_tracks(
+    song: &Song,
+    cfg: &Config,
+    tempo_changes: Vec<(u32, f32)>,
+    mut track_ctxs: Vec<(usize, TrackCtx)>,
+) -> Vec<MidiFileTrack> {
+    let conductor = conductor_track(song, cfg, tempo_changes, &mut track_ctxs);
+    let mut tracks = vec![conductor];
+    match cfg.track_mode {
+        TrackMode::Tracks => tracks.extend(track_ctxs.into_iter().map(|(track, ctx)| {
+            let mut track = MidiFileTrack {
+                name: Some(format!("{}_{}", song.name, track + 1)),
+                events: ctx.events,
+                n_ticks: scale_ticks(ctx.ticks, cfg.ppq).max(cfg.ppq as u32 * 4),
+            };
+            // Note offs capped by the max note length can land before FX events
+            track.sort_events();
+            track
+        })),
+        TrackMode::Instruments => tracks.extend(instrument_tracks(song, cfg, track_ctxs)),
     };
-    let mut song_step = cfg.start_from as usize;
-    while song.song.steps[song_step * 8 + track] < 0xFF {
-        let chain_num = song.song.steps[song_step * 8 + track];
-        collect_chain_events(chain_num, song, &mut ctx);
-        song_step += 1;
+    tracks
+}
+
+/// The first track, holding the tempo map, time signature, a marker at the start of every
+/// song row and a cue point at the start of every chain and phrase
+fn conductor_track(
+    song: &Song,
+    cfg: &Config,
+    mut tempo_changes: Vec<(u32, f32)>,
+    track_ctxs: &mut [(usize, TrackCtx)],
+) -> MidiFileTrack {
+    let (numerator, denominator) = cfg.time_signature.numerator_denominator(song);
+    let mut events: Vec<(u32, MidiFileEvent)> = vec![(
+        0,
+        MetaEvent::TimeSignature {
+            numerator,
+            denominator,
+        }
+        .into(),
+    )];
+
+    let n_ticks = track_ctxs
+        .iter()
+        .map(|(_, ctx)| ctx.ticks)
+        .fold(TICKS_PER_QUARTER_NOTE * 4, u32::max);
+
+    // When several tempo changes land on the same tick, the last one (from the highest track) wins
+    tempo_changes.sort_by_key(|a| a.0);
+    tempo_changes.dedup_by(|later, earlier| {
+        if later.0 == earlier.0 {
+            earlier.1 = later.1;
+        }
+        later.0 == earlier.0
+    });
+    // Tracks that aren't output still change the tempo, but not after the output tracks end
+    events.extend(
+        tempo_changes
+            .into_iter()
+            .filter(|(tick, _)| *tick <= n_ticks)
+            .map(|(tick, bpm)| (tick, MetaEvent::tempo_from_bpm(bpm).into())),
+    );
+
+    // Tracks can reach a row at different times, so mark the earliest.
+    // Rows are keyed by the number of times they have been played, for when tracks loop.
+    let mut rows: BTreeMap<(usize, usize), u32> = BTreeMap::new();
+    for (track, ctx) in track_ctxs.iter_mut() {
+        let mut plays: BTreeMap<usize, usize> = BTreeMap::new();
+        for (tick, row) in ctx.rows.iter() {
+            let play = plays.entry(*row).or_default();
+            let t = rows.entry((*row, *play)).or_insert(*tick);
+            *t = (*t).min(*tick);
+            *play += 1;
+        }
+        events.extend(ctx.cues.drain(..).map(|(tick, cue)| {
+            (
+                tick,
+                MetaEvent::CuePoint(format!("Track {}: {}", *track + 1, cue)).into(),
+            )
+        }));
     }
+    events.extend(
+        rows.into_iter()
+            .map(|((row, _), tick)| (tick, MetaEvent::Marker(format!("Row {:02X}", row)).into())),
+    );
+
+    let mut track = MidiFileTrack {
+        name: Some(song.name.clone()),
+        events: events
+            .into_iter()
+            .map(|(tick, e)| (scale_ticks(tick, cfg.ppq), e))
+            .collect(),
+        n_ticks: scale_ticks(n_ticks, cfg.ppq),
+    };
+    track.sort_events();
+    track
+}
 
-    if ctx.last_note != 255 {
-        ctx.add_note_off(ctx.ticks, Channel::Ch1, ctx.last_note);
+/// Merge the events of every track into one Midi track per instrument
+fn instrument_tracks(
+    song: &Song,
+    cfg: &Config,
+    track_ctxs: Vec<(usize, TrackCtx)>,
+) -> Vec<MidiFileTrack> {
+    let mut n_ticks = cfg.ppq as u32 * 4;
+    let mut instruments: BTreeMap<u8, Vec<(u32, MidiFileEvent)>> = BTreeMap::new();
+    for (_, ctx) in track_ctxs.into_iter() {
+        n_ticks = n_ticks.max(scale_ticks(ctx.ticks, cfg.ppq));
+        for (instrument, mut events) in ctx.instrument_events.into_iter() {
+            instruments
+                .entry(instrument)
+                .or_default()
+                .append(&mut events);
+        }
     }
 
-    MidiFileTrack {
-        name: Some(format!("{}_{}", song.name, track + 1)),
-        events: ctx.events,
-        n_ticks: ctx.ticks.max(TICKS_PER_QUARTER_NOTE * 4),
+    instruments
+        .into_iter()
+        .map(|(instrument, events)| {
+            let name = song
+                .instruments
+                .get(instrument as usize)
+                .map(|i| instrument_name(i).trim())
+                .filter(|n| !n.is_empty())
+                .map(|n| n.to_string())
+                .unwrap_or_else(|| format!("{:02X}", instrument));
+            let mut track = MidiFileTrack {
+                name: Some(format!("{}_{}", song.name, name)),
+                events,
+                n_ticks,
+            };
+            track.sort_events();
+            track
+        })
+        .collect()
+}
+
+/// The row the M8 loops back to when a track reaches an empty row after `row`:
+/// The first row of the block of rows that `row` is in, but no earlier than `first_row`
+fn loop_start(track: usize, row: usize, first_row: usize, song: &Song) -> usize {
+    let mut start = row;
+    while start > first_row && song.song.steps[(start - 1) * 8 + track] < 0xFF {
+        start -= 1;
     }
+    start
+}
+
+/// Render a track. It is played `cfg.repetitions` times, or if `end_tick` is given,
+/// looped until that tick.
+fn collect_track_events(
+    track: usize,
+    song: &Song,
+    cfg: &Config,
+    end_tick: Option<u32>,
+) -> TrackCtx {
+    let mut ctx = TrackCtx::new(track, song, cfg, end_tick);
+    let mut song_step = cfg.rows.start() as usize;
+    // The end row acts as an empty row, so a row range loops on itself
+    let (end_row, first_row) = match cfg.rows.end() {
+        Some(end) => (end as usize, cfg.rows.start() as usize),
+        None => (256, 0),
+    };
+    let mut start_step = 0;
+    let mut repetition = 0;
+    'song: loop {
+        let start_ticks = ctx.ticks;
+        while song_step < end_row && song.song.steps[song_step * 8 + track] < 0xFF {
+            let chain_num = song.song.steps[song_step * 8 + track];
+            ctx.rows.push((ctx.ticks, song_step));
+            match collect_chain_events(chain_num, start_step, song, &mut ctx) {
+                Flow::Continue(step) => start_step = step,
+                Flow::Stop => break 'song,
+            }
+            song_step += 1;
+        }
+
+        repetition += 1;
+        let done = match end_tick {
+            Some(end) => ctx.ticks >= end,
+            None => repetition >= cfg.repetitions,
+        };
+        // Nothing was played, so there is nothing to loop
+        if done || ctx.ticks == start_ticks {
+            break;
+        }
+        song_step = loop_start(track, song_step - 1, first_row, song);
+    }
+
+    ctx.end_note(ctx.ticks);
+    ctx
 }
 
-fn collect_chain_events(chain_num: u8, song: &Song, ctx: &mut TrackCtx) {
+fn collect_chain_events(
+    chain_num: u8,
+    mut start_step: usize,
+    song: &Song,
+    ctx: &mut TrackCtx,
+) -> Flow {
     let chain = &song.chains[chain_num as usize];
     // dbg!(chain);
+    ctx.cues
+        .push((ctx.ticks, format!("Chain {:02X}", chain_num)));
     let mut chain_step = 0;
-    while chain.steps[chain_step].phrase < 0xFF {
+    while chain_step < 16 && chain.steps[chain_step].phrase < 0xFF {
         let cs = &chain.steps[chain_step];
-        ctx.transpose = (cs.transpose as i8) as i16;
-        collect_phrase_events(cs.phrase, song, ctx);
+        ctx.chain_num = chain_num;
+        ctx.chain_transpose = (cs.transpose as i8) as i16;
+        match collect_phrase_events(cs.phrase, start_step, song, ctx) {
+            Flow::Continue(step) => start_step = step,
+            Flow::Stop => return Flow::Stop,
+        }
         chain_step += 1;
     }
+    Flow::Continue(start_step)
 }
 
-fn collect_phrase_events(phrase_num: u8, song: &Song, ctx: &mut TrackCtx) {
+fn collect_phrase_events(
+    phrase_num: u8,
+    start_step: usize,
+    song: &Song,
+    ctx: &mut TrackCtx,
+) -> Flow {
     let phrase = &song.phrases[phrase_num as usize];
-    for i in 0..16 {
+    ctx.phrase_num = phrase_num;
+    ctx.cues
+        .push((ctx.ticks, format!("Phrase {:02X}", phrase_num)));
+    for i in start_step..16 {
+        if ctx.end_tick.is_some_and(|end| ctx.ticks >= end) {
+            return Flow::Stop;
+        }
         let step = &phrase.steps[i];
         // dbg!(step, ctx.ticks);
-        if step.note.0 != 255 {
-            if ctx.last_note != 255 {
-                ctx.add_note_off(ctx.ticks, Channel::Ch1, ctx.last_note);
+        match ctx.play_step(step, i, song) {
+            Some(0xFF) => return Flow::Stop,
+            Some(hop) => return Flow::Continue((hop & 0x0F) as usize),
+            None => (),
+        }
+    }
+    Flow::Continue(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::song_to_midi::*;
+
+    const NO_FX: FX = FX {
+        command: 0xFF,
+        value: 0,
+    };
+
+    /// The M8's default song: Empty, with no instruments
+    fn default_song() -> Song {
+        Song::read(&mut &include_bytes!("../test_songs/DEFAULT.m8s")[..]).unwrap()
+    }
+
+    /// A WavSynth that plays notes like an empty instrument slot would
+    fn wavsynth() -> Instrument {
+        Instrument::WavSynth(WavSynth {
+            number: 0,
+            name: String::new(),
+            transpose: true,
+            table_tick: 1,
+            synth_params: SynthParams {
+                volume: 0xFF,
+                pitch: 0,
+                fine_tune: 0x80,
+                filter_type: 0,
+                filter_cutoff: 0xFF,
+                filter_res: 0,
+                amp: 0,
+                limit: 0,
+                mixer_pan: 0x80,
+                mixer_dry: 0xC0,
+                mixer_chorus: 0,
+                mixer_delay: 0,
+                mixer_reverb: 0,
+                mods: std::array::from_fn(|_| Mod::AHDEnv(AHDEnv::default())),
+            },
+            shape: 0,
+            size: 0x20,
+            mult: 0x80,
+            warp: 0,
+            mirror: 0,
+        })
+    }
+
+    /// A MIDI OUT instrument on the given channel (0-15), without any CCs set
+    fn midi_out(channel: u8) -> Instrument {
+        Instrument::MIDIOut(MIDIOut {
+            number: 0,
+            name: String::new(),
+            transpose: true,
+            table_tick: 1,
+            port: 0,
+            channel,
+            bank_select: 0xFF,
+            program_change: 0,
+            custom_cc: std::array::from_fn(|_| m8_files::ControlChange {
+                number: 0xFF,
+                value: 0,
+            }),
+            mods: std::array::from_fn(|_| Mod::AHDEnv(AHDEnv::default())),
+        })
+    }
+
+    /// A sequencer command, or a command of the `wavsynth` instrument
+    fn fx(command: &str, value: u8) -> FX {
+        let version = default_song().version;
+        let instrument = wavsynth();
+        (0..0xFF)
+            .map(|c| FX { command: c, value })
+            .find(|fx| {
+                fx.command_eq(command, version)
+                    || instrument_command_name(fx, Some(&instrument)) == Some(command)
+            })
+            .expect("Unknown command")
+    }
+
+    fn step(note: u8, fx: &[FX]) -> Step {
+        Step {
+            note: Note(note),
+            velocity: 0x40,
+            instrument: 0,
+            fx1: fx.first().cloned().unwrap_or(NO_FX),
+            fx2: fx.get(1).cloned().unwrap_or(NO_FX),
+            fx3: fx.get(2).cloned().unwrap_or(NO_FX),
+        }
+    }
+
+    fn empty_step() -> Step {
+        step(0xFF, &[])
+    }
+
+    fn groove(ticks: &[u8]) -> Groove {
+        let mut steps = [0xFF; 16];
+        steps[..ticks.len()].copy_from_slice(ticks);
+        Groove { number: 0, steps }
+    }
+
+    fn chain(phrases: &[u8]) -> Chain {
+        let mut chain = Chain::default();
+        for (i, phrase) in phrases.iter().enumerate() {
+            chain.steps[i].phrase = *phrase;
+        }
+        chain
+    }
+
+    /// A song with a single chain on track 1, made up of the given phrases.
+    /// Phrases are given as (step number, step) pairs.
+    fn test_song(phrases: &[&[(usize, Step)]]) -> Song {
+        let mut song = default_song();
+        song.name = "TEST".to_string();
+        song.grooves[1] = groove(&[3, 3]);
+        song.instruments[0] = wavsynth();
+        song.song.steps[0] = 0;
+        song.phrases.truncate(phrases.len());
+        for (phrase, steps) in song.phrases.iter_mut().zip(phrases.iter()) {
+            phrase.steps = std::array::from_fn(|_| empty_step());
+            for (n, step) in steps.iter() {
+                phrase.steps[*n] = step.clone();
             }
-            ctx.add_note_on(ctx.ticks, Channel::Ch1, step.note.0, step.velocity);
         }
-        ctx.change_groove(step, song);
-        ctx.ticks += ctx.groove_ticks(i);
+        song.chains = vec![chain(&(0..phrases.len() as u8).collect::<Vec<_>>())];
+        song
+    }
+
+    /// Renders only track 1, leaving M8 notes untransposed
+    fn track_1() -> Config {
+        Config {
+            tracks: "1".parse().unwrap(),
+            global_transpose: 0,
+            ..Config::default()
+        }
+    }
+
+    /// (tick, is note on, note) of the note events of track 1
+    fn notes(song: &Song) -> Vec<(u32, bool, u8)> {
+        note_events(&song_to_midi_file(song, &track_1()), 1)
+    }
+
+    /// (tick, channel, message) of the channel voice events of a track
+    fn channel_msgs(midi_file: &MidiFile, track: usize) -> Vec<(u32, Channel, ChannelVoiceMsg)> {
+        midi_file.tracks[track]
+            .events
+            .iter()
+            .filter_map(|(tick, e)| match e {
+                MidiFileEvent::Midi(MidiMsg::ChannelVoice { channel, msg }) => {
+                    Some((*tick, *channel, *msg))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// (tick, is note on, note) of the note events of a track
+    fn note_events(midi_file: &MidiFile, track: usize) -> Vec<(u32, bool, u8)> {
+        channel_msgs(midi_file, track)
+            .into_iter()
+            .filter_map(|(tick, _, msg)| match msg {
+                ChannelVoiceMsg::NoteOn { note, .. } => Some((tick, true, note)),
+                ChannelVoiceMsg::NoteOff { note, .. } => Some((tick, false, note)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// (tick, note) of the note ons of a track
+    fn note_ons(midi_file: &MidiFile, track: usize) -> Vec<(u32, u8)> {
+        channel_msgs(midi_file, track)
+            .into_iter()
+            .filter_map(|(tick, _, msg)| match msg {
+                ChannelVoiceMsg::NoteOn { note, .. } => Some((tick, note)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// (channel, note) of the note ons of a track
+    fn note_channels(midi_file: &MidiFile, track: usize) -> Vec<(Channel, u8)> {
+        channel_msgs(midi_file, track)
+            .into_iter()
+            .filter_map(|(_, channel, msg)| match msg {
+                ChannelVoiceMsg::NoteOn { note, .. } => Some((channel, note)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Velocities of the note ons of a track
+    fn velocities(midi_file: &MidiFile, track: usize) -> Vec<u8> {
+        channel_msgs(midi_file, track)
+            .into_iter()
+            .filter_map(|(_, _, msg)| match msg {
+                ChannelVoiceMsg::NoteOn { velocity, .. } => Some(velocity),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// (tick, channel, program) of the program changes of a track
+    fn program_changes(midi_file: &MidiFile, track: usize) -> Vec<(u32, Channel, u8)> {
+        channel_msgs(midi_file, track)
+            .into_iter()
+            .filter_map(|(tick, channel, msg)| match msg {
+                ChannelVoiceMsg::ProgramChange { program } => Some((tick, channel, program)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// (tick, event) of the tempo changes of the conductor track
+    fn tempos(midi_file: &MidiFile) -> Vec<(u32, MidiFileEvent)> {
+        midi_file.tracks[0]
+            .events
+            .iter()
+            .filter(|(_, e)| matches!(e, MidiFileEvent::Meta(MetaEvent::Tempo(_))))
+            .cloned()
+            .collect()
+    }
+
+    #[test]
+    fn test_no_fx() {
+        let song = test_song(&[&[(0, step(60, &[])), (4, step(62, &[]))]]);
+        assert_eq!(
+            notes(&song),
+            vec![
+                (0, true, 60),
+                (24, false, 60),
+                (24, true, 62),
+                (96, false, 62)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_grv() {
+        let song = test_song(&[&[(0, step(60, &[fx("GRV", 1)])), (1, step(62, &[]))]]);
+        assert_eq!(
+            notes(&song),
+            vec![
+                (0, true, 60),
+                (3, false, 60),
+                (3, true, 62),
+                (48, false, 62)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tpo() {
+        let tempos = |song: &Song| tempos(&song_to_midi_file(song, &Config::default()));
+        let song = test_song(&[&[(2, step(0xFF, &[fx("TPO", 0x90)]))]]);
+        assert_eq!(
+            tempos(&song),
+            vec![
+                (0, MetaEvent::tempo_from_bpm(120.0).into()),
+                (12, MetaEvent::tempo_from_bpm(144.0).into())
+            ]
+        );
+
+        // Delayed tempo changes happen when the step is triggered
+        let song = test_song(&[&[(2, step(0xFF, &[fx("DEL", 2), fx("TPO", 0x90)]))]]);
+        assert_eq!(
+            tempos(&song),
+            vec![
+                (0, MetaEvent::tempo_from_bpm(120.0).into()),
+                (14, MetaEvent::tempo_from_bpm(144.0).into())
+            ]
+        );
+
+        // Only one tempo change is output per tick, the one from the last track
+        let mut song = test_song(&[
+            &[
+                (0, step(0xFF, &[fx("TPO", 0x80)])),
+                (2, step(0xFF, &[fx("TPO", 0x90)])),
+            ],
+            &[(2, step(0xFF, &[fx("TPO", 0xA0)]))],
+        ]);
+        song.chains = vec![chain(&[0]), chain(&[1])];
+        song.song.steps[1] = 1;
+        assert_eq!(
+            tempos(&song),
+            vec![
+                (0, MetaEvent::tempo_from_bpm(128.0).into()),
+                (12, MetaEvent::tempo_from_bpm(160.0).into())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tpo_unselected_track() {
+        // Track 2 is longer than track 1, and changes the tempo each time it plays phrase 01
+        let mut song = test_song(&[
+            &[(0, step(60, &[]))],
+            &[(8, step(0xFF, &[fx("TPO", 0x90)]))],
+        ]);
+        song.chains = vec![chain(&[0]), chain(&[1, 1, 1])];
+        song.song.steps[1] = 1;
+        let cfg = track_1();
+        let midi_file = song_to_midi_file(&song, &cfg);
+        assert!(midi_file.to_midi().is_ok());
+        assert_eq!(
+            tempos(&midi_file),
+            vec![
+                (0, MetaEvent::tempo_from_bpm(120.0).into()),
+                (48, MetaEvent::tempo_from_bpm(144.0).into())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_conductor_track() {
+        let mut song = test_song(&[&[(0, step(60, &[]))], &[(0, step(62, &[]))]]);
+        song.song.steps[8] = 0;
+        let cfg = track_1();
+        let conductor = &song_to_midi_file(&song, &cfg).tracks[0];
+        let meta = |tick: u32, m: MetaEvent| (tick, MidiFileEvent::from(m));
+        assert_eq!(
+            conductor.events,
+            vec![
+                meta(
+                    0,
+                    MetaEvent::TimeSignature {
+                        numerator: 4,
+                        denominator: 4
+                    }
+                ),
+                meta(0, MetaEvent::tempo_from_bpm(120.0)),
+                meta(0, MetaEvent::CuePoint("Track 1: Chain 00".to_string())),
+                meta(0, MetaEvent::CuePoint("Track 1: Phrase 00".to_string())),
+                meta(0, MetaEvent::Marker("Row 00".to_string())),
+                meta(96, MetaEvent::CuePoint("Track 1: Phrase 01".to_string())),
+                meta(192, MetaEvent::CuePoint("Track 1: Chain 00".to_string())),
+                meta(192, MetaEvent::CuePoint("Track 1: Phrase 00".to_string())),
+                meta(192, MetaEvent::Marker("Row 01".to_string())),
+                meta(288, MetaEvent::CuePoint("Track 1: Phrase 01".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let song = test_song(&[&[
+            (0, step(60, &[fx("VOL", 0x80), fx("RET", 0x23)])),
+            (4, step(62, &[fx("PIT", 0xC0), fx("TPO", 0x90)])),
+        ]]);
+        let midi_file = song_to_midi_file(&song, &Config::default());
+        let mut read = MidiFile::from_midi(&midi_file.to_midi().unwrap()).unwrap();
+        // End of Track is written a tick after the end
+        for track in read.tracks.iter_mut() {
+            track.n_ticks -= 1;
+        }
+        assert_eq!(read, midi_file);
+    }
+
+    #[test]
+    fn test_max_note_length_events_sorted() {
+        let song = test_song(&[&[
+            (0, step(60, &[])),
+            (5, step(0xFF, &[fx("VOL", 0x80)])),
+            (8, step(62, &[])),
+        ]]);
+        let cfg = track_1().max_note_len(1.0);
+        let midi_file = song_to_midi_file(&song, &cfg);
+        assert!(midi_file.tracks[1]
+            .events
+            .windows(2)
+            .all(|e| e[0].0 <= e[1].0));
+        assert!(midi_file.to_midi().is_ok());
+    }
+
+    #[test]
+    fn test_ppq() {
+        let song = test_song(&[&[(0, step(60, &[fx("DEL", 1)])), (4, step(62, &[]))]]);
+        let cfg = Config {
+            ppq: 960,
+            ..track_1()
+        }
+        .max_note_len(0.5);
+        assert_eq!(cfg.max_note_length[0], 480);
+        let midi_file = song_to_midi_file(&song, &cfg);
+        assert_eq!(midi_file.ticks_per_quarter_note, 960);
+        let notes: Vec<u32> = midi_file.tracks[1]
+            .events
+            .iter()
+            .map(|(tick, _)| *tick)
+            .collect();
+        assert_eq!(notes, vec![40, 520, 960, 1440]);
+        assert_eq!(midi_file.tracks[1].n_ticks, 3840);
+        // The conductor track is scaled too
+        assert_eq!(midi_file.tracks[0].n_ticks, 3840);
+    }
+
+    #[test]
+    fn test_repetitions() {
+        let mut song = test_song(&[&[(0, step(60, &[]))], &[(0, step(62, &[]))]]);
+        song.chains = vec![chain(&[0]), chain(&[1])];
+        song.song.steps[8] = 1;
+        let cfg = Config {
+            repetitions: 2,
+            ..track_1()
+        };
+        assert_eq!(
+            note_ons(&song_to_midi_file(&song, &cfg), 1),
+            vec![(0, 60), (96, 62), (192, 60), (288, 62)]
+        );
+
+        // Starting in the middle of a block loops back to its first row
+        let cfg = Config {
+            rows: RowRange::new(1, None).unwrap(),
+            ..cfg
+        };
+        let midi_file = song_to_midi_file(&song, &cfg);
+        assert_eq!(note_ons(&midi_file, 1), vec![(0, 62), (96, 60), (192, 62)]);
+        assert_eq!(midi_file.tracks[1].n_ticks, 288);
+    }
+
+    #[test]
+    fn test_end_at() {
+        let mut song = test_song(&[&[(0, step(60, &[]))], &[(0, step(62, &[]))]]);
+        song.chains = vec![chain(&[0]), chain(&[1])];
+        song.song.steps[8] = 1;
+        song.song.steps[16] = 0;
+        let cfg = Config {
+            rows: "1..2".parse().unwrap(),
+            ..track_1()
+        };
+        let midi_file = song_to_midi_file(&song, &cfg);
+        assert_eq!(note_ons(&midi_file, 1), vec![(0, 62)]);
+        // The note is ended at the row boundary
+        assert_eq!(midi_file.tracks[1].events.last().map(|e| e.0), Some(96));
+
+        // Row ranges loop on themselves
+        let cfg = Config {
+            repetitions: 2,
+            ..cfg
+        };
+        let midi_file = song_to_midi_file(&song, &cfg);
+        assert_eq!(note_ons(&midi_file, 1), vec![(0, 62), (96, 62)]);
+    }
+
+    #[test]
+    fn test_clips() {
+        let mut song = test_song(&[&[(0, step(60, &[]))], &[(4, step(62, &[]))]]);
+        song.chains = vec![chain(&[0, 1]), chain(&[1])];
+        let cfg = Config {
+            global_transpose: 0,
+            repetitions: 2,
+            ..Config::default()
+        };
+
+        let midi_file = chain_to_midi_file(&song, 1, &cfg).unwrap();
+        assert_eq!(midi_file.tracks.len(), 2);
+        assert_eq!(midi_file.tracks[1].name, Some("TEST_Chain 01".to_string()));
+        assert_eq!(note_ons(&midi_file, 1), vec![(24, 62), (120, 62)]);
+        assert_eq!(midi_file.tracks[1].n_ticks, 192);
+
+        let midi_file = phrase_to_midi_file(&song, 0, &cfg).unwrap();
+        assert_eq!(midi_file.tracks[1].name, Some("TEST_Phrase 00".to_string()));
+        assert_eq!(note_ons(&midi_file, 1), vec![(0, 60), (96, 60)]);
+
+        assert!(chain_to_midi_file(&song, 2, &cfg).is_none());
+        assert!(phrase_to_midi_file(&song, 2, &cfg).is_none());
+    }
+
+    #[test]
+    fn test_tracks() {
+        let mut song = test_song(&[
+            &[(0, step(60, &[]))],
+            &[(0, step(62, &[]))],
+            &[(0, step(64, &[]))],
+        ]);
+        song.chains = vec![chain(&[0]), chain(&[1]), chain(&[2])];
+        song.song.steps[1] = 1;
+        song.song.steps[2] = 2;
+        let cfg = Config {
+            tracks: "1,3".parse().unwrap(),
+            global_transpose: 0,
+            ..Config::default()
+        };
+        let (midi_file, report) = song_to_midi_file_with_report(&song, &cfg);
+        assert_eq!(midi_file.tracks.len(), 3);
+        assert_eq!(midi_file.tracks[2].name, Some("TEST_3".to_string()));
+        assert_eq!(note_ons(&midi_file, 2), vec![(0, 64)]);
+        assert_eq!(
+            report.tracks.iter().map(|t| t.track).collect::<Vec<_>>(),
+            vec![1, 3]
+        );
+
+        // Clips use the settings of the first track
+        let cfg = Config {
+            tracks: "3,2".parse().unwrap(),
+            ..cfg
+        };
+        let clip = phrase_to_midi_file(&song, 0, &cfg).unwrap();
+        assert_eq!(clip.tracks[1].name, Some("TEST_Phrase 00".to_string()));
+        let cfg = Config {
+            tracks: TrackSet::none(),
+            ..cfg
+        };
+        assert_eq!(song_to_midi_file(&song, &cfg).tracks.len(), 1);
+    }
+
+    #[test]
+    fn test_instrument_mode() {
+        let with_instrument = |note, instrument| {
+            let mut s = step(note, &[]);
+            s.instrument = instrument;
+            s
+        };
+        let mut song = test_song(&[&[
+            (0, step(60, &[])),
+            (4, with_instrument(64, 2)),
+            (8, step(62, &[])),
+        ]]);
+        let mut bass = wavsynth();
+        if let Instrument::WavSynth(i) = &mut bass {
+            i.name = "BASS".to_string();
+        }
+        song.instruments[2] = bass;
+        let cfg = Config {
+            track_mode: TrackMode::Instruments,
+            ..track_1()
+        };
+        let midi_file = song_to_midi_file(&song, &cfg);
+        assert_eq!(midi_file.tracks.len(), 3);
+        // Instruments without a name are named after their number
+        assert_eq!(midi_file.tracks[1].name, Some("TEST_00".to_string()));
+        assert_eq!(midi_file.tracks[2].name, Some("TEST_BASS".to_string()));
+        assert_eq!(
+            note_events(&midi_file, 1),
+            vec![
+                (0, true, 60),
+                (24, false, 60),
+                (48, true, 62),
+                (96, false, 62)
+            ]
+        );
+        assert_eq!(
+            note_events(&midi_file, 2),
+            vec![(24, true, 64), (48, false, 64)]
+        );
+    }
+
+    #[test]
+    fn test_loop_until_longest() {
+        let mut song = test_song(&[&[(0, step(60, &[]))], &[(8, step(62, &[]))]]);
+        song.chains = vec![chain(&[0]), chain(&[1, 1, 1])];
+        song.song.steps[1] = 1;
+        let cfg = Config {
+            tracks: "1,2".parse().unwrap(),
+            global_transpose: 0,
+            loop_until_longest: true,
+            ..Config::default()
+        };
+        let midi_file = song_to_midi_file(&song, &cfg);
+        assert_eq!(note_ons(&midi_file, 1), vec![(0, 60), (96, 60), (192, 60)]);
+        assert_eq!(
+            note_ons(&midi_file, 2),
+            vec![(48, 62), (144, 62), (240, 62)]
+        );
+        assert_eq!(midi_file.tracks[1].n_ticks, 288);
+        assert_eq!(midi_file.tracks[2].n_ticks, 288);
+    }
+
+    #[test]
+    fn test_loop_until_longest_empty() {
+        // Track 2 has no chains, so there is no longest track to loop until
+        let song = test_song(&[&[(0, step(60, &[])), (8, step(0xFF, &[fx("TPO", 0x90)]))]]);
+        let cfg = Config {
+            tracks: "2".parse().unwrap(),
+            loop_until_longest: true,
+            ..Config::default()
+        };
+        let midi_file = song_to_midi_file(&song, &cfg);
+        assert_eq!(midi_file.tracks[0].n_ticks, 96);
+        assert_eq!(midi_file.tracks[1].n_ticks, 96);
+        assert_eq!(
+            tempos(&midi_file),
+            vec![
+                (0, MetaEvent::tempo_from_bpm(120.0).into()),
+                (48, MetaEvent::tempo_from_bpm(144.0).into())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_phrase_time_signature() {
+        let mut song = test_song(&[]);
+        let ts = TimeSignature::Phrase;
+        assert_eq!(ts.numerator_denominator(&song), (4, 4));
+        song.grooves[0] = groove(&[6, 3]);
+        assert_eq!(ts.numerator_denominator(&song), (3, 4));
+        song.grooves[0] = groove(&[6, 6, 3]);
+        assert_eq!(ts.numerator_denominator(&song), (27, 32));
+        // Phrases that can't be expressed as a time signature fall back to 4/4
+        song.grooves[0] = groove(&[7]);
+        assert_eq!(ts.numerator_denominator(&song), (4, 4));
+        assert_eq!("7/8".parse(), Ok(TimeSignature::Fixed(7, 8)));
+        assert_eq!("phrase".parse(), Ok(TimeSignature::Phrase));
+        assert!("7/6".parse::<TimeSignature>().is_err());
+    }
+
+    #[test]
+    fn test_fx_map() {
+        let song = test_song(&[&[(1, step(60, &[fx("VOL", 0x80), fx("PIT", 0xC0)]))]]);
+        let cfg = track_1();
+        let msgs: Vec<(u32, ChannelVoiceMsg)> = channel_msgs(&song_to_midi_file(&song, &cfg), 1)
+            .into_iter()
+            .map(|(tick, _, msg)| (tick, msg))
+            .take(3)
+            .collect();
+        assert_eq!(
+            msgs,
+            vec![
+                (
+                    6,
+                    ChannelVoiceMsg::ControlChange {
+                        control: midi_msg::ControlChange::Undefined {
+                            control: 7,
+                            value: 0x40
+                        }
+                    }
+                ),
+                (6, ChannelVoiceMsg::PitchBend { bend: 0x1000 }),
+                (
+                    6,
+                    ChannelVoiceMsg::NoteOn {
+                        note: 60,
+                        velocity: 0x40
+                    }
+                )
+            ]
+        );
+    }
+
+    #[test]
+    fn test_program_changes() {
+        let mut inst_1 = step(60, &[]);
+        inst_1.instrument = 1;
+        let song = test_song(&[&[(0, step(60, &[])), (1, step(60, &[])), (2, inst_1)]]);
+        let cfg = Config {
+            program_changes: true,
+            ..track_1()
+        };
+        assert_eq!(
+            program_changes(&song_to_midi_file(&song, &cfg), 1),
+            vec![(0, Channel::Ch1, 0), (12, Channel::Ch1, 1)]
+        );
+    }
+
+    #[test]
+    fn test_channels() {
+        let mut inst_0x11 = step(62, &[]);
+        inst_0x11.instrument = 0x11;
+        let mut song = test_song(&[&[(0, step(60, &[]))], &[(0, inst_0x11)]]);
+        song.chains = vec![chain(&[0]), chain(&[1])];
+        song.song.steps[1] = 1;
+
+        let mut track_channels = [Channel::Ch1; 8];
+        track_channels[0] = Channel::Ch3;
+        track_channels[1] = Channel::Ch10;
+        let cfg = Config {
+            tracks: "1,2".parse().unwrap(),
+            channels: track_channels,
+            program_changes: true,
+            ..Config::default()
+        };
+        let midi_file = song_to_midi_file(&song, &cfg);
+        assert_eq!(program_changes(&midi_file, 1), vec![(0, Channel::Ch3, 0)]);
+        assert_eq!(note_channels(&midi_file, 1), vec![(Channel::Ch3, 96)]);
+        assert_eq!(
+            program_changes(&midi_file, 2),
+            vec![(0, Channel::Ch10, 0x11)]
+        );
+        assert_eq!(note_channels(&midi_file, 2), vec![(Channel::Ch10, 98)]);
+
+        // Instrument 0x11 is on channel 2, MIDI OUT instruments use their own channel
+        song.instruments[0] = midi_out(5);
+        let cfg = Config {
+            instrument_channels: true,
+            ..cfg
+        };
+        let midi_file = song_to_midi_file(&song, &cfg);
+        assert_eq!(program_changes(&midi_file, 1), vec![(0, Channel::Ch6, 0)]);
+        assert_eq!(note_channels(&midi_file, 1), vec![(Channel::Ch6, 96)]);
+        assert_eq!(
+            program_changes(&midi_file, 2),
+            vec![(0, Channel::Ch2, 0x11)]
+        );
+        assert_eq!(note_channels(&midi_file, 2), vec![(Channel::Ch2, 98)]);
+    }
+
+    #[test]
+    fn test_separate_channels() {
+        let mut song = test_song(&[&[(0, step(60, &[]))], &[(0, step(62, &[]))]]);
+        song.chains = vec![chain(&[0]), chain(&[1])];
+        song.song.steps[1] = 1;
+        song.instruments[1] = wavsynth();
+        let merged_note_channels = |song: &Song, cfg: Config| {
+            note_channels(&song_to_midi_file(song, &cfg).to_single_track(), 0)
+        };
+        let cfg = Config {
+            global_transpose: 0,
+            ..Config::default()
+        };
+        assert_eq!(
+            merged_note_channels(&song, cfg.separate_channels()),
+            vec![(Channel::Ch1, 60), (Channel::Ch2, 62)]
+        );
+
+        // In instrument mode, each instrument gets its own channel
+        let mut inst_1 = step(62, &[]);
+        inst_1.instrument = 1;
+        song.phrases[1].steps[0] = inst_1;
+        let cfg = Config {
+            global_transpose: 0,
+            track_mode: TrackMode::Instruments,
+            ..Config::default()
+        };
+        assert_eq!(
+            merged_note_channels(&song, cfg.separate_channels()),
+            vec![(Channel::Ch1, 60), (Channel::Ch2, 62)]
+        );
+    }
+
+    #[test]
+    fn test_velocity() {
+        let velocity = |v| {
+            let mut s = step(60, &[]);
+            s.velocity = v;
+            s
+        };
+        let song = test_song(&[&[
+            (0, velocity(0xFF)),
+            (1, velocity(0x20)),
+            (2, velocity(0xFF)),
+            (3, velocity(0x00)),
+        ]]);
+        let cfg = track_1();
+        assert_eq!(
+            velocities(&song_to_midi_file(&song, &cfg), 1),
+            vec![0x7F, 0x20, 0x20, 0x01]
+        );
+        let cfg = Config {
+            velocity_curve: VelocityCurve::Fixed(100),
+            ..cfg
+        };
+        assert_eq!(
+            velocities(&song_to_midi_file(&song, &cfg), 1),
+            vec![100, 100, 100, 100]
+        );
+    }
+
+    #[test]
+    fn test_velocity_curve() {
+        assert_eq!(VelocityCurve::Linear.apply(0xFF), 0x7F);
+        assert_eq!(VelocityCurve::Soft.apply(0x20), 64);
+        assert_eq!(VelocityCurve::Hard.apply(0x40), 32);
+        assert_eq!(VelocityCurve::Hard.apply(0x01), 1);
+        assert_eq!("soft".parse(), Ok(VelocityCurve::Soft));
+        assert_eq!("90".parse(), Ok(VelocityCurve::Fixed(90)));
+        assert!("0".parse::<VelocityCurve>().is_err());
+    }
+
+    #[test]
+    fn test_transpose() {
+        let mut song = test_song(&[&[(0, step(60, &[]))], &[(0, step(100, &[]))]]);
+        song.transpose = 0x0C;
+        song.chains[0].steps[0].transpose = 0xFE;
+        song.chains[0].steps[1].transpose = 0x20;
+        // Song and chain transpose add up, and can push notes out of range
+        assert_eq!(
+            notes(&song),
+            vec![
+                (0, true, 70),
+                (96, false, 70),
+                (96, true, 127),
+                (192, false, 127)
+            ]
+        );
+
+        let cfg = Config {
+            out_of_range_notes: OutOfRangeNotes::Drop,
+            ..track_1()
+        };
+        let (midi_file, report) = song_to_midi_file_with_report(&song, &cfg);
+        assert_eq!(note_ons(&midi_file, 1), vec![(0, 70)]);
+        assert_eq!(
+            report.tracks[0].out_of_range_notes,
+            vec![OutOfRangeNote {
+                location: Location {
+                    row: Some(0),
+                    chain: Some(0),
+                    phrase: 1,
+                    step: 0
+                },
+                note: 144,
+                output: None
+            }]
+        );
+    }
+
+    #[test]
+    fn test_report() {
+        let song = test_song(&[
+            &[(0, step(60, &[fx("ARP", 0x37)])), (4, step(62, &[]))],
+            &[(0, step(64, &[fx("VOL", 0x40), fx("ARP", 0x37)]))],
+        ]);
+        let cfg = Config {
+            repetitions: 2,
+            ..track_1()
+        }
+        .max_note_len(2.0);
+        let (_, report) = song_to_midi_file_with_report(&song, &cfg);
+        assert_eq!(report.tracks.len(), 1);
+        let track = &report.tracks[0];
+        assert_eq!(track.track, 1);
+        assert_eq!(track.notes, 6);
+        assert_eq!(track.bars, 4.0);
+        // Repeated steps are only reported once
+        assert_eq!(
+            track
+                .unsupported_fx
+                .iter()
+                .map(|fx| (fx.location.phrase, fx.location.step, fx.command.as_str()))
+                .collect::<Vec<_>>(),
+            vec![(0, 0, "ARP"), (1, 0, "ARP")]
+        );
+        assert_eq!(
+            track
+                .truncated_notes
+                .iter()
+                .map(|n| (n.location.phrase, n.location.step, n.note))
+                .collect::<Vec<_>>(),
+            vec![(0, 4, 62), (1, 0, 64), (0, 4, 62), (1, 0, 64)]
+        );
+        assert_eq!(
+            track.to_string(),
+            "Track 1: 6 notes, 4.0 bars, 2 unsupported FX commands, 4 truncated notes"
+        );
+    }
+
+    #[test]
+    fn test_report_chords() {
+        // MIDI OUT's ADD and CHD commands aren't expanded into chords
+        let add = FX {
+            command: 0x84,
+            value: 0x04,
+        };
+        let chd = FX {
+            command: 0x85,
+            value: 0x47,
+        };
+        let mut song = test_song(&[&[(0, step(60, &[add, chd]))]]);
+        song.instruments[0] = midi_out(0);
+        let cfg = Config {
+            arpeggios: Arpeggios::Chord,
+            ..track_1()
+        };
+        let (midi_file, report) = song_to_midi_file_with_report(&song, &cfg);
+        assert_eq!(note_ons(&midi_file, 1), vec![(0, 60)]);
+        assert_eq!(
+            report.tracks[0]
+                .unsupported_fx
+                .iter()
+                .map(|fx| fx.command.as_str())
+                .collect::<Vec<_>>(),
+            vec!["ADD", "CHD"]
+        );
+    }
+
+    #[test]
+    fn test_fx_command_name() {
+        let mut song = default_song();
+        let command = |command| FX { command, value: 0 };
+        assert_eq!(fx_command_name(&command(0x15), &song, None), "TPO");
+        assert_eq!(fx_command_name(&command(0x1A), &song, None), "XCW");
+        song.version = Version {
+            major: 3,
+            minor: 0,
+            patch: 0,
+        };
+        assert_eq!(fx_command_name(&command(0x15), &song, None), "THO");
+        assert_eq!(fx_command_name(&command(0x1A), &song, None), "OFF");
+        assert_eq!(fx_command_name(&command(0x70), &song, None), "70");
+        let instrument = wavsynth();
+        assert_eq!(
+            fx_command_name(&command(0x80), &song, Some(&instrument)),
+            "VOL"
+        );
+    }
+
+    #[test]
+    fn test_out_of_range_notes() {
+        assert_eq!(OutOfRangeNotes::Clamp.apply(60), Some(60));
+        assert_eq!(OutOfRangeNotes::Clamp.apply(-3), Some(0));
+        assert_eq!(OutOfRangeNotes::Clamp.apply(130), Some(127));
+        assert_eq!(OutOfRangeNotes::Fold.apply(-3), Some(9));
+        assert_eq!(OutOfRangeNotes::Fold.apply(-24), Some(0));
+        assert_eq!(OutOfRangeNotes::Fold.apply(128), Some(116));
+        assert_eq!(OutOfRangeNotes::Fold.apply(139), Some(127));
+        assert_eq!(OutOfRangeNotes::Fold.apply(140), Some(116));
+        assert_eq!(OutOfRangeNotes::Drop.apply(128), None);
+        assert_eq!(OutOfRangeNotes::Drop.apply(127), Some(127));
+        assert_eq!("fold".parse(), Ok(OutOfRangeNotes::Fold));
+        assert!("wrap".parse::<OutOfRangeNotes>().is_err());
+    }
+
+    #[test]
+    fn test_arp() {
+        let arp_notes = |song: &Song, arpeggios| {
+            let cfg = Config {
+                arpeggios,
+                ..track_1()
+            };
+            note_events(&song_to_midi_file(song, &cfg), 1)
+        };
+        let song = test_song(&[&[(0, step(60, &[fx("ARP", 0x47)])), (4, step(62, &[]))]]);
+        assert_eq!(
+            arp_notes(&song, Arpeggios::Chord),
+            vec![
+                (0, true, 60),
+                (0, true, 64),
+                (0, true, 67),
+                (24, false, 60),
+                (24, false, 64),
+                (24, false, 67),
+                (24, true, 62),
+                (96, false, 62)
+            ]
+        );
+        assert_eq!(
+            arp_notes(&song, Arpeggios::Off),
+            vec![
+                (0, true, 60),
+                (24, false, 60),
+                (24, true, 62),
+                (96, false, 62)
+            ]
+        );
+        let cfg = track_1();
+        let (_, report) = song_to_midi_file_with_report(&song, &cfg);
+        assert_eq!(report.tracks[0].unsupported_fx[0].command, "ARP");
+
+        let song = test_song(&[&[(0, step(60, &[fx("ARP", 0x47)])), (1, step(62, &[]))]]);
+        assert_eq!(
+            arp_notes(&song, Arpeggios::Arpeggiate(2)),
+            vec![
+                (0, true, 60),
+                (2, false, 60),
+                (2, true, 64),
+                (4, false, 64),
+                (4, true, 67),
+                (6, false, 67),
+                (6, true, 62),
+                (96, false, 62)
+            ]
+        );
+
+        // An ARP command on its own changes the note that is playing
+        let song = test_song(&[&[(0, step(60, &[])), (1, step(0xFF, &[fx("ARP", 0x30)]))]]);
+        assert_eq!(
+            arp_notes(&song, Arpeggios::Chord),
+            vec![
+                (0, true, 60),
+                (6, false, 60),
+                (6, true, 60),
+                (6, true, 63),
+                (96, false, 60),
+                (96, false, 63)
+            ]
+        );
+
+        assert_eq!("chord".parse(), Ok(Arpeggios::Chord));
+        assert_eq!("3".parse(), Ok(Arpeggios::Arpeggiate(3)));
+        assert!("0".parse::<Arpeggios>().is_err());
+    }
+
+    #[test]
+    fn test_del() {
+        let song = test_song(&[&[(0, step(60, &[])), (1, step(62, &[fx("DEL", 3)]))]]);
+        assert_eq!(
+            notes(&song),
+            vec![
+                (0, true, 60),
+                (9, false, 60),
+                (9, true, 62),
+                (96, false, 62)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_kil() {
+        let song = test_song(&[&[(0, step(60, &[fx("KIL", 2)])), (4, step(62, &[]))]]);
+        assert_eq!(
+            notes(&song),
+            vec![
+                (0, true, 60),
+                (2, false, 60),
+                (24, true, 62),
+                (96, false, 62)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_off_command() {
+        let off = FX {
+            command: 0x1A,
+            value: 2,
+        };
+        let mut song = test_song(&[&[(0, step(60, &[off])), (4, step(62, &[]))]]);
+        song.version = Version {
+            major: 3,
+            minor: 0,
+            patch: 0,
+        };
+        assert_eq!(
+            notes(&song),
+            vec![
+                (0, true, 60),
+                (2, false, 60),
+                (24, true, 62),
+                (96, false, 62)
+            ]
+        );
+        // Before firmware 3, the command is XCW, which doesn't end the note
+        song.version = default_song().version;
+        assert_eq!(
+            notes(&song),
+            vec![
+                (0, true, 60),
+                (24, false, 60),
+                (24, true, 62),
+                (96, false, 62)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_voices() {
+        // A note that is killed as it starts can't be heard, so it isn't output
+        let song = test_song(&[&[(0, step(60, &[fx("KIL", 0)])), (4, step(62, &[]))]]);
+        assert_eq!(notes(&song), vec![(24, true, 62), (96, false, 62)]);
+
+        // When tracks are merged, a note that starts as another of the same pitch ends
+        // comes after its note off
+        let mut song = test_song(&[
+            &[(4, step(60, &[]))],
+            &[(0, step(60, &[])), (4, step(62, &[]))],
+        ]);
+        song.chains = vec![chain(&[0]), chain(&[1])];
+        song.song.steps[1] = 1;
+        let cfg = Config {
+            tracks: "1,2".parse().unwrap(),
+            global_transpose: 0,
+            track_mode: TrackMode::Instruments,
+            ..Config::default()
+        };
+        let midi_file = song_to_midi_file(&song, &cfg);
+        assert_eq!(
+            note_events(&midi_file, 1),
+            vec![
+                (0, true, 60),
+                (24, false, 60),
+                (24, true, 60),
+                (24, true, 62),
+                (96, false, 60),
+                (96, false, 62)
+            ]
+        );
+        assert_eq!(midi_file.tracks.len(), 2);
+    }
+
+    #[test]
+    fn test_ret() {
+        let song = test_song(&[&[(0, step(60, &[fx("RET", 0x02)])), (1, step(62, &[]))]]);
+        assert_eq!(
+            notes(&song),
+            vec![
+                (0, true, 60),
+                (2, false, 60),
+                (2, true, 60),
+                (4, false, 60),
+                (4, true, 60),
+                (6, false, 60),
+                (6, true, 62),
+                (96, false, 62)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ret_kil() {
+        let song = test_song(&[&[(0, step(60, &[fx("RET", 0x02), fx("KIL", 3)]))]]);
+        assert_eq!(
+            notes(&song),
+            vec![(0, true, 60), (2, false, 60), (2, true, 60), (3, false, 60)]
+        );
+    }
+
+    #[test]
+    fn test_note_off() {
+        let off = |fx: &[FX]| step(NOTE_OFF, fx);
+        // Description, phrase steps, and the (tick, is note on, note) events they output
+        let fixtures = vec![
+            (
+                "OFF ends the note",
+                vec![(0, step(60, &[])), (2, off(&[]))],
+                vec![(0, true, 60), (12, false, 60)],
+            ),
+            (
+                "Any note from 80 is an OFF",
+                vec![(0, step(60, &[])), (2, step(0xFE, &[]))],
+                vec![(0, true, 60), (12, false, 60)],
+            ),
+            (
+                "DEL delays the OFF",
+                vec![(0, step(60, &[])), (2, off(&[fx("DEL", 3)]))],
+                vec![(0, true, 60), (15, false, 60)],
+            ),
+            (
+                "OFF without a note playing does nothing",
+                vec![(2, off(&[]))],
+                vec![],
+            ),
+            (
+                "A note after an OFF",
+                vec![(0, step(60, &[])), (1, off(&[])), (2, step(62, &[]))],
+                vec![
+                    (0, true, 60),
+                    (6, false, 60),
+                    (12, true, 62),
+                    (96, false, 62),
+                ],
+            ),
+            (
+                "REP after an OFF plays the note again",
+                vec![
+                    (0, step(60, &[])),
+                    (1, off(&[])),
+                    (2, step(0xFF, &[fx("REP", 0)])),
+                ],
+                vec![
+                    (0, true, 60),
+                    (6, false, 60),
+                    (12, true, 60),
+                    (96, false, 60),
+                ],
+            ),
+            (
+                "REP on an OFF step doesn't play the note",
+                vec![(0, step(60, &[])), (1, off(&[fx("REP", 0)]))],
+                vec![(0, true, 60), (6, false, 60)],
+            ),
+            (
+                "KIL on an empty step cuts the note that is playing",
+                vec![(0, step(60, &[])), (1, step(0xFF, &[fx("KIL", 2)]))],
+                vec![(0, true, 60), (8, false, 60)],
+            ),
+            (
+                "KIL carries over into the following steps",
+                vec![(0, step(60, &[fx("KIL", 0x10)]))],
+                vec![(0, true, 60), (16, false, 60)],
+            ),
+            (
+                "A note after a KIL isn't cut",
+                vec![(0, step(60, &[fx("KIL", 0x10)])), (2, step(62, &[]))],
+                vec![
+                    (0, true, 60),
+                    (12, false, 60),
+                    (12, true, 62),
+                    (96, false, 62),
+                ],
+            ),
+            (
+                "OFF before a KIL ends the note",
+                vec![(0, step(60, &[fx("KIL", 0x10)])), (1, off(&[]))],
+                vec![(0, true, 60), (6, false, 60)],
+            ),
+        ];
+        for (description, phrase, expected) in fixtures {
+            assert_eq!(notes(&test_song(&[&phrase])), expected, "{}", description);
+        }
+    }
+
+    #[test]
+    fn test_rep() {
+        let song = test_song(&[&[(0, step(60, &[])), (2, step(0xFF, &[fx("REP", 0)]))]]);
+        assert_eq!(
+            notes(&song),
+            vec![
+                (0, true, 60),
+                (12, false, 60),
+                (12, true, 60),
+                (96, false, 60)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_hop() {
+        let song = test_song(&[
+            &[(0, step(60, &[])), (1, step(0xFF, &[fx("HOP", 4)]))],
+            &[(2, step(62, &[])), (4, step(64, &[]))],
+        ]);
+        assert_eq!(
+            notes(&song),
+            vec![
+                (0, true, 60),
+                (12, false, 60),
+                (12, true, 64),
+                (84, false, 64)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_hop_stop() {
+        let song = test_song(&[
+            &[(0, step(60, &[])), (1, step(0xFF, &[fx("HOP", 0xFF)]))],
+            &[(0, step(62, &[]))],
+        ]);
+        assert_eq!(notes(&song), vec![(0, true, 60), (12, false, 60)]);
+    }
+
+    /// Set the transpose, velocity and FX of a table row
+    fn table_row(
+        song: &mut Song,
+        table: usize,
+        row: usize,
+        transpose: u8,
+        velocity: u8,
+        fx: &[FX],
+    ) {
+        let step = &mut song.tables[table].steps[row];
+        step.transpose = transpose;
+        step.velocity = velocity;
+        step.fx1 = fx.first().cloned().unwrap_or(NO_FX);
+        step.fx2 = fx.get(1).cloned().unwrap_or(NO_FX);
+        step.fx3 = fx.get(2).cloned().unwrap_or(NO_FX);
+    }
+
+    #[test]
+    fn test_table() {
+        let mut song = test_song(&[&[(0, step(60, &[]))]]);
+        table_row(&mut song, 0, 0, 0, 0xFF, &[fx("TIC", 0x10)]);
+        table_row(&mut song, 0, 1, 0x0C, 0x20, &[]);
+        table_row(&mut song, 0, 2, 0xF4, 0xFF, &[fx("HOP", 1)]);
+        assert_eq!(
+            notes(&song),
+            vec![
+                (0, true, 60),
+                (16, false, 60),
+                (16, true, 72),
+                (32, false, 72),
+                (32, true, 48),
+                (48, false, 48),
+                (48, true, 72),
+                (64, false, 72),
+                (64, true, 48),
+                (80, false, 48),
+                (80, true, 72),
+                (96, false, 72)
+            ]
+        );
+        // Rows without a velocity use the velocity of the step
+        let cfg = track_1();
+        assert_eq!(
+            velocities(&song_to_midi_file(&song, &cfg), 1),
+            vec![0x40, 0x20, 0x40, 0x20, 0x40, 0x20]
+        );
+
+        // Tables stop at the max note length
+        let cfg = Config {
+            global_transpose: 0,
+            ..cfg
+        }
+        .max_note_len(1.0);
+        assert_eq!(
+            note_events(&song_to_midi_file(&song, &cfg), 1),
+            vec![
+                (0, true, 60),
+                (16, false, 60),
+                (16, true, 72),
+                (40, false, 72)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_table_hop_stop() {
+        let mut song = test_song(&[&[(0, step(60, &[]))]]);
+        table_row(&mut song, 0, 0, 0, 0xFF, &[fx("TIC", 0x10)]);
+        table_row(&mut song, 0, 1, 0x0C, 0xFF, &[fx("HOP", 0xFF)]);
+        assert_eq!(
+            notes(&song),
+            vec![
+                (0, true, 60),
+                (16, false, 60),
+                (16, true, 72),
+                (96, false, 72)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tbl() {
+        let mut song = test_song(&[&[(0, step(60, &[fx("TBL", 1)])), (4, step(62, &[]))]]);
+        table_row(&mut song, 1, 0, 0x0C, 0xFF, &[fx("HOP", 0xFF)]);
+        // The table keeps playing for the following notes of the instrument
+        assert_eq!(
+            notes(&song),
+            vec![
+                (0, true, 72),
+                (24, false, 72),
+                (24, true, 74),
+                (96, false, 74)
+            ]
+        );
+
+        // TBL applies to the instrument that a step changes to, rather than its own table
+        let mut inst_2 = step(60, &[fx("TBL", 5)]);
+        inst_2.instrument = 2;
+        let mut song = test_song(&[&[(0, inst_2)]]);
+        song.instruments[2] = wavsynth();
+        table_row(&mut song, 5, 0, 0x0C, 0xFF, &[fx("HOP", 0xFF)]);
+        assert_eq!(notes(&song), vec![(0, true, 72), (96, false, 72)]);
+    }
+
+    #[test]
+    fn test_table_out_of_range() {
+        let mut song = test_song(&[&[(0, step(120, &[]))]]);
+        table_row(&mut song, 0, 0, 0, 0xFF, &[fx("TIC", 0x10), fx("KIL", 2)]);
+        table_row(&mut song, 0, 1, 0x10, 0xFF, &[]);
+        assert_eq!(
+            notes(&song),
+            vec![
+                (0, true, 120),
+                (16, false, 120),
+                (16, true, 127),
+                (32, false, 127),
+                (32, true, 120),
+                (96, false, 120)
+            ]
+        );
+
+        let cfg = Config {
+            out_of_range_notes: OutOfRangeNotes::Drop,
+            ..track_1()
+        };
+        let (midi_file, report) = song_to_midi_file_with_report(&song, &cfg);
+        assert_eq!(note_ons(&midi_file, 1), vec![(0, 120), (32, 120)]);
+        let track = &report.tracks[0];
+        assert_eq!(
+            track
+                .out_of_range_notes
+                .iter()
+                .map(|n| (n.note, n.output))
+                .collect::<Vec<_>>(),
+            vec![(136, None)]
+        );
+        // Only TIC and HOP are played in tables
+        assert_eq!(
+            track
+                .unsupported_fx
+                .iter()
+                .map(|fx| fx.command.as_str())
+                .collect::<Vec<_>>(),
+            vec!["KIL"]
+        );
     }
 }
@@ -0,0 +1,132 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// A set of song tracks (1-8), stored as a bitmask with bit 0 for track 1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrackSet(u8);
+
+impl TrackSet {
+    /// All eight tracks
+    pub fn all() -> Self {
+        Self(0xFF)
+    }
+
+    /// No tracks
+    pub fn none() -> Self {
+        Self(0)
+    }
+
+    pub fn from_bits(bits: u8) -> Self {
+        Self(bits)
+    }
+
+    pub fn bits(&self) -> u8 {
+        self.0
+    }
+
+    /// The set of the given tracks, which must be from 1-8
+    pub fn from_tracks(tracks: impl IntoIterator<Item = usize>) -> Result<Self, String> {
+        let mut set = Self::none();
+        for track in tracks {
+            if !(1..=8).contains(&track) {
+                return Err(format!("Invalid track {}. Tracks go from 1 to 8", track));
+            }
+            set.set(track, true);
+        }
+        Ok(set)
+    }
+
+    /// Whether `track` (1-8) is in the set
+    pub fn contains(&self, track: usize) -> bool {
+        (1..=8).contains(&track) && self.0 & (1 << (track - 1)) != 0
+    }
+
+    /// Add `track` (1-8) to the set, or remove it. Other tracks are ignored.
+    pub fn set(&mut self, track: usize, enabled: bool) {
+        if !(1..=8).contains(&track) {
+            return;
+        }
+        if enabled {
+            self.0 |= 1 << (track - 1);
+        } else {
+            self.0 &= !(1 << (track - 1));
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// The tracks in the set, in order
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        (1..=8).filter(|t| self.contains(*t))
+    }
+
+    /// The lowest track in the set
+    pub fn first(&self) -> Option<usize> {
+        self.iter().next()
+    }
+}
+
+impl Default for TrackSet {
+    fn default() -> Self {
+        Self::all()
+    }
+}
+
+impl FromStr for TrackSet {
+    type Err = String;
+
+    /// Parses a comma separated list of tracks, e.g. `1,3,6`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let tracks = s
+            .split(',')
+            .map(|t| {
+                t.trim().parse::<usize>().map_err(|_| {
+                    format!(
+                        "Invalid track `{}`. Expected a comma separated list of tracks from 1-8",
+                        t.trim()
+                    )
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Self::from_tracks(tracks)
+    }
+}
+
+impl fmt::Display for TrackSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let tracks: Vec<String> = self.iter().map(|t| t.to_string()).collect();
+        write!(f, "{}", tracks.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::track_set::*;
+
+    #[test]
+    fn test_track_set() {
+        let tracks: TrackSet = "1, 3,6".parse().unwrap();
+        assert_eq!(tracks.bits(), 0b100101);
+        assert!(tracks.contains(3));
+        assert!(!tracks.contains(2));
+        assert!(!tracks.contains(0));
+        assert!(!tracks.contains(9));
+        assert_eq!(tracks.iter().collect::<Vec<_>>(), vec![1, 3, 6]);
+        assert_eq!(tracks.first(), Some(1));
+        assert_eq!(tracks.to_string(), "1,3,6");
+        assert_eq!(TrackSet::all().to_string(), "1,2,3,4,5,6,7,8");
+        assert_eq!(TrackSet::none().first(), None);
+        assert!("0".parse::<TrackSet>().is_err());
+        assert!("9".parse::<TrackSet>().is_err());
+        assert!("1,x".parse::<TrackSet>().is_err());
+        assert!("".parse::<TrackSet>().is_err());
+
+        let mut tracks = TrackSet::all();
+        tracks.set(1, false);
+        tracks.set(9, false);
+        assert_eq!(tracks.bits(), 0xFE);
+        assert_eq!(tracks.first(), Some(2));
+    }
+}
@@ -12,8 +12,16 @@ use lemna_nih_plug::nih_plug::{
     prelude::ParamPtr,
 };
 use m8_files::Song;
+use midi_m8_core::fx_map::{FxMap, FxMapping};
 use midi_m8_core::midi_file::MidiFile;
-use midi_m8_core::song_to_midi::{song_to_midi_file, Config, TICKS_PER_QUARTER_NOTE};
+use midi_m8_core::row_range::RowRange;
+use midi_m8_core::song_to_midi::{
+    chain_to_midi_file, phrase_to_midi_file, song_to_midi_file, song_to_midi_file_with_report,
+    Arpeggios, Config, OutOfRangeNotes, TimeSignature, TrackMode, VelocityCurve,
+    TICKS_PER_QUARTER_NOTE,
+};
+use midi_m8_core::track_set::TrackSet;
+use midi_msg::Channel;
 use serde::{Deserialize, Serialize};
 use temp_file::TempFile;
 
@@ -26,6 +34,57 @@ pub const MID_GRAY: Color = color!(0x5F, 0x5F, 0x5F);
 pub const LIGHT_GRAY: Color = color!(0xDE, 0xDE, 0xDE);
 pub const BLUE: Color = color!(0x00, 0xE5, 0xEE);
 
+/// Resolutions the MIDI data can be output at
+const PPQ_OPTIONS: [u16; 5] = [TICKS_PER_QUARTER_NOTE as u16, 96, 192, 480, 960];
+
+/// FX commands whose mapping can be changed, and the label of their parameter
+const MAPPED_FX_COMMANDS: [&str; 5] = ["VOL", "PAN", "CUT", "RES", "PIT"];
+
+/// The mapping of an FX mapping parameter: 0 is off, 1-120 are CC 0-119,
+/// 121 is pitch bend and 122 aftertouch
+fn fx_mapping_from_param(v: i32) -> Option<FxMapping> {
+    match v {
+        1..=120 => Some(FxMapping::ControlChange(v as u8 - 1)),
+        121 => Some(FxMapping::PitchBend),
+        122 => Some(FxMapping::ChannelPressure),
+        _ => None,
+    }
+}
+
+/// A parameter for the mapping of an FX command, defaulting to its mapping in `FxMap::default`
+fn fx_mapping_param(command: &str) -> Arc<IntParam> {
+    let default = match FxMap::default().mappings.get(command) {
+        Some(FxMapping::ControlChange(cc)) => *cc as i32 + 1,
+        Some(FxMapping::PitchBend) => 121,
+        Some(FxMapping::ChannelPressure) => 122,
+        _ => 0,
+    };
+    Arc::new(
+        IntParam::new(
+            format!("{command} Mapping"),
+            default,
+            IntRange::Linear { min: 0, max: 122 },
+        )
+        .with_value_to_string(Arc::new(|v| match fx_mapping_from_param(v) {
+            Some(FxMapping::ControlChange(cc)) => format!("CC{cc}"),
+            Some(FxMapping::PitchBend) => "PB".into(),
+            Some(FxMapping::ChannelPressure) => "AT".into(),
+            _ => "OFF".into(),
+        }))
+        .with_string_to_value(Arc::new(|s| {
+            if s.trim().eq_ignore_ascii_case("off") {
+                return Some(0);
+            }
+            match s.parse::<FxMapping>().ok()? {
+                FxMapping::ControlChange(cc) => Some(cc as i32 + 1),
+                FxMapping::PitchBend => Some(121),
+                FxMapping::ChannelPressure => Some(122),
+                FxMapping::InstrumentControlChange(_) => None,
+            }
+        })),
+    )
+}
+
 #[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
 pub struct MaybeFile(Option<PathBuf>);
 
@@ -33,23 +92,112 @@ pub struct MaybeFile(Option<PathBuf>);
 pub struct M8Params {
     #[id = "start"]
     pub start: Arc<IntParam>,
+    /// Row to stop before. 256 plays until the end of the song.
+    #[id = "end"]
+    pub end: Arc<IntParam>,
     #[id = "max_len"]
     pub max_len: Arc<FloatParam>,
     #[id = "transpose"]
     pub transpose: Arc<IntParam>,
+    /// 0 is the track number, 1-16 are fixed channels, 17 is the instrument's channel
+    #[id = "channel"]
+    pub channel: Arc<IntParam>,
+    #[id = "instrument_mode"]
+    pub instrument_mode: Arc<BoolParam>,
+    /// Output FX commands as CC, pitch bend and aftertouch
+    #[id = "fx_map"]
+    pub fx_map: Arc<BoolParam>,
+    /// What each of `MAPPED_FX_COMMANDS` is output as, see `fx_mapping_from_param`
+    #[id = "vol_mapping"]
+    pub vol_mapping: Arc<IntParam>,
+    #[id = "pan_mapping"]
+    pub pan_mapping: Arc<IntParam>,
+    #[id = "cut_mapping"]
+    pub cut_mapping: Arc<IntParam>,
+    #[id = "res_mapping"]
+    pub res_mapping: Arc<IntParam>,
+    #[id = "pit_mapping"]
+    pub pit_mapping: Arc<IntParam>,
+    /// 0 is off, 1 outputs program changes, 2 also outputs bank selects
+    #[id = "program_changes"]
+    pub program_changes: Arc<IntParam>,
+    /// One bar per phrase, rather than 4/4
+    #[id = "phrase_time_signature"]
+    pub phrase_time_signature: Arc<BoolParam>,
+    /// 0 is linear, 1 soft and 2 hard
+    #[id = "velocity_curve"]
+    pub velocity_curve: Arc<IntParam>,
+    /// 0 clamps out of range notes, 1 folds them by octaves and 2 drops them
+    #[id = "out_of_range_notes"]
+    pub out_of_range_notes: Arc<IntParam>,
+    /// 0 only plays the notes of steps, 1 plays ARP commands as chords,
+    /// 2-13 arpeggiate them every 1-12 ticks
+    #[id = "arpeggios"]
+    pub arpeggios: Arc<IntParam>,
+    /// Index into `PPQ_OPTIONS`
+    #[id = "ppq"]
+    pub ppq: Arc<IntParam>,
+    /// Merge all tracks into one, as a format 0 file
+    #[id = "single_track"]
+    pub single_track: Arc<BoolParam>,
+    #[id = "repetitions"]
+    pub repetitions: Arc<IntParam>,
+    #[id = "loop_until_longest"]
+    pub loop_until_longest: Arc<BoolParam>,
+    /// Song tracks included in ALL TRACKS, as the bits of a `TrackSet`
+    #[id = "tracks"]
+    pub tracks: Arc<IntParam>,
+    /// Chain that can be dragged on its own
+    #[id = "clip_chain"]
+    pub clip_chain: Arc<IntParam>,
+    /// Phrase that can be dragged on its own
+    #[id = "clip_phrase"]
+    pub clip_phrase: Arc<IntParam>,
 
     #[persist = "file"]
     pub file: Arc<RwLock<MaybeFile>>,
 }
 
+impl M8Params {
+    /// The mapping parameter of each of `MAPPED_FX_COMMANDS`
+    pub fn fx_mappings(&self) -> [(&'static str, &Arc<IntParam>); 5] {
+        [
+            (MAPPED_FX_COMMANDS[0], &self.vol_mapping),
+            (MAPPED_FX_COMMANDS[1], &self.pan_mapping),
+            (MAPPED_FX_COMMANDS[2], &self.cut_mapping),
+            (MAPPED_FX_COMMANDS[3], &self.res_mapping),
+            (MAPPED_FX_COMMANDS[4], &self.pit_mapping),
+        ]
+    }
+}
+
 impl Default for M8Params {
     fn default() -> Self {
         Self {
-            start: Arc::new(IntParam::new(
-                "Start",
-                0,
-                IntRange::Linear { min: 0, max: 255 },
-            )),
+            start: Arc::new(
+                IntParam::new("Start", 0, IntRange::Linear { min: 0, max: 255 })
+                    .with_value_to_string(Arc::new(|v| format!("{v:02X}")))
+                    .with_string_to_value(Arc::new(|s| {
+                        RowRange::parse_row(s).ok().map(|r| r as i32)
+                    })),
+            ),
+            end: Arc::new(
+                IntParam::new("End", 256, IntRange::Linear { min: 1, max: 256 })
+                    .with_value_to_string(Arc::new(|v| {
+                        if v == 256 {
+                            "END".into()
+                        } else {
+                            format!("{v:02X}")
+                        }
+                    }))
+                    .with_string_to_value(Arc::new(|s| {
+                        if s.trim().eq_ignore_ascii_case("end") {
+                            Some(256)
+                        } else {
+                            RowRange::parse_row(s).ok().map(|r| r as i32)
+                        }
+                    })),
+            ),
             max_len: Arc::new(
                 FloatParam::new(
                     "Max Note Length",
@@ -70,6 +218,113 @@ impl Default for M8Params {
                 36,
                 IntRange::Linear { min: 0, max: 72 },
             )),
+            channel: Arc::new(
+                IntParam::new("Channel", 1, IntRange::Linear { min: 0, max: 17 })
+                    .with_value_to_string(Arc::new(|v| match v {
+                        0 => "TRK".into(),
+                        17 => "INST".into(),
+                        _ => format!("{v}"),
+                    })),
+            ),
+            instrument_mode: Arc::new(
+                BoolParam::new("Instrument Mode", false)
+                    .with_value_to_string(Arc::new(|v| if v { "INST" } else { "TRK" }.into())),
+            ),
+            fx_map: Arc::new(
+                BoolParam::new("FX to CC", true)
+                    .with_value_to_string(Arc::new(|v| if v { "ON" } else { "OFF" }.into())),
+            ),
+            vol_mapping: fx_mapping_param("VOL"),
+            pan_mapping: fx_mapping_param("PAN"),
+            cut_mapping: fx_mapping_param("CUT"),
+            res_mapping: fx_mapping_param("RES"),
+            pit_mapping: fx_mapping_param("PIT"),
+            program_changes: Arc::new(
+                IntParam::new("Program Changes", 0, IntRange::Linear { min: 0, max: 2 })
+                    .with_value_to_string(Arc::new(|v| match v {
+                        0 => "OFF".into(),
+                        1 => "ON".into(),
+                        _ => "BANK".into(),
+                    })),
+            ),
+            phrase_time_signature: Arc::new(
+                BoolParam::new("Phrase Time Signature", false)
+                    .with_value_to_string(Arc::new(|v| if v { "PHRASE" } else { "4/4" }.into())),
+            ),
+            velocity_curve: Arc::new(
+                IntParam::new("Velocity Curve", 0, IntRange::Linear { min: 0, max: 2 })
+                    .with_value_to_string(Arc::new(|v| match v {
+                        0 => "LIN".into(),
+                        1 => "SOFT".into(),
+                        _ => "HARD".into(),
+                    })),
+            ),
+            out_of_range_notes: Arc::new(
+                IntParam::new("Out Of Range Notes", 0, IntRange::Linear { min: 0, max: 2 })
+                    .with_value_to_string(Arc::new(|v| match v {
+                        0 => "CLAMP".into(),
+                        1 => "FOLD".into(),
+                        _ => "DROP".into(),
+                    })),
+            ),
+            arpeggios: Arc::new(
+                IntParam::new("Arpeggios", 0, IntRange::Linear { min: 0, max: 13 })
+                    .with_value_to_string(Arc::new(|v| match v {
+                        0 => "OFF".into(),
+                        1 => "CHORD".into(),
+                        _ => format!("{}", v - 1),
+                    })),
+            ),
+            ppq: Arc::new(
+                IntParam::new(
+                    "PPQ",
+                    0,
+                    IntRange::Linear {
+                        min: 0,
+                        max: PPQ_OPTIONS.len() as i32 - 1,
+                    },
+                )
+                .with_value_to_string(Arc::new(|v| format!("{}", PPQ_OPTIONS[v as usize]))),
+            ),
+            single_track: Arc::new(
+                BoolParam::new("Format 0", false)
+                    .with_value_to_string(Arc::new(|v| if v { "0" } else { "1" }.into())),
+            ),
+            repetitions: Arc::new(IntParam::new(
+                "Repetitions",
+                1,
+                IntRange::Linear { min: 1, max: 16 },
+            )),
+            loop_until_longest: Arc::new(
+                BoolParam::new("Loop Until Longest", false)
+                    .with_value_to_string(Arc::new(|v| if v { "ON" } else { "OFF" }.into())),
+            ),
+            tracks: Arc::new(
+                IntParam::new("Tracks", 0xFF, IntRange::Linear { min: 0, max: 0xFF })
+                    .with_value_to_string(Arc::new(|v| {
+                        let tracks = TrackSet::from_bits(v as u8);
+                        if tracks.is_empty() {
+                            "NONE".into()
+                        } else {
+                            tracks.to_string()
+                        }
+                    }))
+                    .with_string_to_value(Arc::new(|s| {
+                        if s.trim().eq_ignore_ascii_case("none") {
+                            Some(0)
+                        } else {
+                            s.parse::<TrackSet>().ok().map(|t| t.bits() as i32)
+                        }
+                    })),
+            ),
+            clip_chain: Arc::new(
+                IntParam::new("Chain", 0, IntRange::Linear { min: 0, max: 254 })
+                    .with_value_to_string(Arc::new(|v| format!("{v:02X}"))),
+            ),
+            clip_phrase: Arc::new(
+                IntParam::new("Phrase", 0, IntRange::Linear { min: 0, max: 254 })
+                    .with_value_to_string(Arc::new(|v| format!("{v:02X}"))),
+            ),
             file: Default::default(),
         }
     }
@@ -78,7 +333,11 @@ impl Default for M8Params {
 #[derive(Debug)]
 pub struct MidiTempFiles {
     pub all: TempFile,
-    pub tracks: [Option<TempFile>; 8],
+    /// Label and file of each track. Tracks without any events have no file.
+    pub tracks: Vec<(String, Option<TempFile>)>,
+    /// The selected chain and phrase, if they hold any events
+    pub chain: Option<TempFile>,
+    pub phrase: Option<TempFile>,
 }
 
 #[derive(Debug)]
@@ -96,6 +355,8 @@ pub struct AppState {
     pub params: Arc<M8Params>,
     pub gui_context: Option<Arc<dyn GuiContext>>,
     song: Option<Arc<MidiTempFiles>>,
+    /// Summary of the conversion of each track
+    report: Option<String>,
     error: Option<String>, // TODO
 }
 
@@ -132,6 +393,35 @@ impl lemna::Component for M8PlugApp {
     }
 
     fn view(&self) -> Option<Node> {
+        let mut footer = node!(
+            widgets::Div::new(),
+            [size: [Auto, 30],
+             direction: Row,
+             padding: [5],
+            ],
+        )
+        .push(node!(widgets::Button::new(txt!("?"))
+            .style("radius", 20.0)
+            .tool_tip("Select or drag a M8 song file. Then drag the MIDI data from the desired track or all tracks.\n\nYou can adjust the max note length, the starting and ending song positions, how many times to play the song and whether to loop shorter tracks until the longest one ends, the amount by which to transpose M8 note numbers to turn them into MIDI note numbers (default is 36) and whether notes that end up out of the MIDI range are clamped, folded by octaves or dropped, whether ARP commands are output as chords or as arpeggios that move every given number of ticks, the MIDI channel (TRK uses the track number, INST the instrument's channel), whether to output one track per song track or per instrument, whether to output FX commands (VOL, PAN, CUT, RES, PIT, CCA-CCH) as CC and pitch bend, and what VOL, PAN, CUT, RES and PIT are output as (a CC, PB for pitch bend, AT for aftertouch, or OFF), whether to output program changes (and bank selects) when the instrument changes, whether the time signature is 4/4 or one bar per phrase, the curve used to turn M8 velocities into MIDI velocities, the resolution (PPQ) of the MIDI data, and whether to write MIDI format 1 or merge all tracks into a single track (format 0). Click ON/OFF under a track to leave it out of ALL TRACKS. A single chain or phrase can also be picked and dragged on its own, using the settings of track 1. Hold shift to fine-tune.".into())));
+        if let Some(report) = &self.state_ref().report {
+            footer = footer.push(node!(
+                widgets::Button::new(txt!("REPORT"))
+                    .style("padding", 1.5)
+                    .tool_tip(report.clone()),
+                [margin: [0, 5]]
+            ));
+        }
+        footer = footer.push(node!(
+            widgets::Button::new(
+                txt!(format!("MIDI-M8 V{}", env!("CARGO_PKG_VERSION"))),
+            ).style("padding", 1.5)
+             .on_click(Box::new(|| msg!(AppMsg::OpenSite))),
+            [
+                position_type: Absolute,
+                position: [Auto, Auto, Auto, 0.0]
+            ]
+        ));
+
         Some(
             node!(
                 widgets::Div::new().bg(DARK_GRAY),
@@ -150,33 +440,13 @@ impl lemna::Component for M8PlugApp {
                 [size: [Auto, 90]]
             ))
             .push(node!(
-                DragSources::new(self.state_ref().song.clone()),
+                DragSources::new(
+                    self.state_ref().song.clone(),
+                    self.state_ref().params.clone()
+                ),
                 [size: [Auto, 150]]
             ))
-            // Footer
-            .push(
-                node!(
-                    widgets::Div::new(),
-                    [size: [Auto, 30],
-                     direction: Row,
-                     padding: [5],
-                    ],
-                )
-                .push(node!(widgets::Button::new(
-                    txt!("?"))
-                            .style("radius", 20.0)
-                            .tool_tip("Select or drag a M8 song file. Then drag the MIDI data from the desired track or all tracks.\n\nYou can adjust the max note length, the starting song position, and the amount by which to transpose M8 note numbers to turn them into MIDI note numbers (default is 36). Hold shift to fine-tune.".into())))
-                .push(node!(
-                    widgets::Button::new(
-                        txt!(format!("MIDI-M8 V{}", env!("CARGO_PKG_VERSION"))),
-                    ).style("padding", 1.5)
-                     .on_click(Box::new(|| msg!(AppMsg::OpenSite))),
-                    [
-                        position_type: Absolute,
-                        position: [Auto, Auto, Auto, 0.0]
-                    ]
-                )),
-            ),
+            .push(footer),
         )
     }
 
@@ -248,45 +518,167 @@ impl M8PlugApp {
             let mut f = File::open(p)?;
             let song = Song::read(&mut f)?;
             let mut config = Config {
-                start_from: self.state_ref().params.start.value() as u8,
                 global_transpose: self.state_ref().params.transpose.value() as i16,
+                ppq: PPQ_OPTIONS[self.state_ref().params.ppq.value() as usize],
+                repetitions: self.state_ref().params.repetitions.value() as u32,
+                loop_until_longest: self.state_ref().params.loop_until_longest.value(),
                 ..Config::default()
             };
+            let start = self.state_ref().params.start.value() as u8;
+            let end = self.state_ref().params.end.value();
+            // An end row that isn't after the start plays until the end of the song
+            config.rows = RowRange::new(start, (end < 256).then_some(end as u8))
+                .or_else(|_| RowRange::new(start, None))?;
 
             let max_len = self.state_ref().params.max_len.value();
             if max_len > 0.0 {
                 for i in 0..8 {
-                    config.max_note_length[i] = (self.state_ref().params.max_len.value()
-                        * TICKS_PER_QUARTER_NOTE as f32)
-                        as u32;
+                    config.max_note_length[i] =
+                        (self.state_ref().params.max_len.value() * config.ppq as f32) as u32;
+                }
+            }
+
+            match self.state_ref().params.channel.value() {
+                0 => {
+                    for (i, channel) in config.channels.iter_mut().enumerate() {
+                        *channel = Channel::from_u8(i as u8);
+                    }
+                }
+                17 => config.instrument_channels = true,
+                c => config.channels = [Channel::from_u8((c - 1) as u8); 8],
+            }
+            if self.state_ref().params.instrument_mode.value() {
+                config.track_mode = TrackMode::Instruments;
+            }
+            if self.state_ref().params.fx_map.value() {
+                for (command, param) in self.state_ref().params.fx_mappings() {
+                    config
+                        .fx_map
+                        .set(command, fx_mapping_from_param(param.value()));
                 }
+            } else {
+                config.fx_map = FxMap::empty();
             }
+            let program_changes = self.state_ref().params.program_changes.value();
+            config.program_changes = program_changes > 0;
+            config.bank_select = program_changes > 1;
+            if self.state_ref().params.phrase_time_signature.value() {
+                config.time_signature = TimeSignature::Phrase;
+            }
+            config.velocity_curve = match self.state_ref().params.velocity_curve.value() {
+                0 => VelocityCurve::Linear,
+                1 => VelocityCurve::Soft,
+                _ => VelocityCurve::Hard,
+            };
+            config.out_of_range_notes = match self.state_ref().params.out_of_range_notes.value() {
+                0 => OutOfRangeNotes::Clamp,
+                1 => OutOfRangeNotes::Fold,
+                _ => OutOfRangeNotes::Drop,
+            };
+            config.arpeggios = match self.state_ref().params.arpeggios.value() {
+                0 => Arpeggios::Off,
+                1 => Arpeggios::Chord,
+                v => Arpeggios::Arpeggiate(v as u8 - 1),
+            };
 
-            let midi_file = song_to_midi_file(&song, &config);
-            Some(Arc::new(Self::midi_file_to_paths(midi_file)?))
+            let (midi_file, report) = song_to_midi_file_with_report(&song, &config);
+            // Tracks that are turned off are left out of ALL TRACKS, but can still be dragged
+            // on their own. In instrument mode, there are no song tracks to turn off.
+            let tracks = TrackSet::from_bits(self.state_ref().params.tracks.value() as u8);
+            let all = if config.track_mode == TrackMode::Tracks && tracks != TrackSet::all() {
+                config.tracks = tracks;
+                let all = song_to_midi_file(&song, &config);
+                config.tracks = TrackSet::all();
+                Some(all)
+            } else {
+                None
+            };
+            let single_track = self.state_ref().params.single_track.value();
+            let mut files = Self::midi_file_to_paths(midi_file, all, &song.name, single_track)?;
+            let chain = self.state_ref().params.clip_chain.value() as u8;
+            files.chain =
+                Self::clip_to_path(chain_to_midi_file(&song, chain, &config), single_track)?;
+            let phrase = self.state_ref().params.clip_phrase.value() as u8;
+            files.phrase =
+                Self::clip_to_path(phrase_to_midi_file(&song, phrase, &config), single_track)?;
+            let report = report
+                .tracks
+                .iter()
+                .map(|t| t.to_string())
+                .collect::<Vec<_>>()
+                .join("\n");
+            Some((Arc::new(files), report))
         } else {
             None
         };
-        self.state_mut().song = v;
+        let (song, report) = v.unzip();
+        self.state_mut().song = song;
+        self.state_mut().report = report;
         Ok(())
     }
 
-    fn midi_file_to_paths(midi_file: MidiFile) -> Result<MidiTempFiles, Box<dyn Error>> {
-        let all = midi_file.to_midi();
+    /// `all` is the file for ALL TRACKS, if it differs from `midi_file`
+    fn midi_file_to_paths(
+        midi_file: MidiFile,
+        all: Option<MidiFile>,
+        song_name: &str,
+        single_track: bool,
+    ) -> Result<MidiTempFiles, Box<dyn Error>> {
+        let all_file = all.as_ref().unwrap_or(&midi_file);
+        let all = if single_track {
+            all_file.to_single_track().to_midi()?
+        } else {
+            all_file.to_midi()?
+        };
         let mut f = MidiTempFiles {
             all: TempFile::with_suffix(".midi")?.with_contents(&all[..])?,
-            tracks: Default::default(),
+            tracks: vec![],
+            chain: None,
+            phrase: None,
         };
 
-        for (i, track) in midi_file.tracks.iter().enumerate() {
-            if track.events.is_empty() {
-                continue;
-            }
-            let t = midi_file.track_to_midi(i);
-            f.tracks[i] = Some(TempFile::with_suffix(".midi")?.with_contents(&t[..])?);
+        let prefix = format!("{}_", song_name);
+        // The first track is the conductor track, which holds no notes
+        for (i, track) in midi_file.tracks.iter().enumerate().skip(1) {
+            // Tracks are named after the song, followed by the track number or instrument
+            let label = track
+                .name
+                .as_ref()
+                .map(|n| n.strip_prefix(&prefix).unwrap_or(n).to_string())
+                .unwrap_or_else(|| format!("{}", i));
+            let file = if track.events.is_empty() {
+                None
+            } else {
+                let t = if single_track {
+                    midi_file.track_file(i)?.to_single_track().to_midi()?
+                } else {
+                    midi_file.track_to_midi(i)?
+                };
+                Some(TempFile::with_suffix(".midi")?.with_contents(&t[..])?)
+            };
+            f.tracks.push((label, file));
         }
         Ok(f)
     }
+
+    fn clip_to_path(
+        clip: Option<MidiFile>,
+        single_track: bool,
+    ) -> Result<Option<TempFile>, Box<dyn Error>> {
+        match clip {
+            Some(c) if c.tracks.iter().skip(1).any(|t| !t.events.is_empty()) => {
+                let midi = if single_track {
+                    c.to_single_track().to_midi()?
+                } else {
+                    c.to_midi()?
+                };
+                Ok(Some(
+                    TempFile::with_suffix(".midi")?.with_contents(&midi[..])?,
+                ))
+            }
+            _ => Ok(None),
+        }
+    }
 }
 
 fn note_len_to_string(v: f32) -> String {
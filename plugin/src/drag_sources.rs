@@ -1,16 +1,20 @@
 use std::sync::Arc;
 
-use crate::app::*;
+use crate::{app::*, basic_param::BasicParam};
 use lemna::{self, style::HorizontalPosition, widgets, *};
+use lemna_nih_plug::nih_plug::params::{IntParam, Param};
+use midi_m8_core::track_set::TrackSet;
+use temp_file::TempFile;
 
 #[derive(Debug)]
 pub struct DragSources {
     song: Option<Arc<MidiTempFiles>>,
+    params: Arc<M8Params>,
 }
 
 impl DragSources {
-    pub fn new(song: Option<Arc<MidiTempFiles>>) -> Self {
-        Self { song }
+    pub fn new(song: Option<Arc<MidiTempFiles>>, params: Arc<M8Params>) -> Self {
+        Self { song, params }
     }
 }
 
@@ -28,13 +32,41 @@ impl lemna::Component for DragSources {
             )
             .push(node!(
                 TracksDragSource {
-                    song: self.song.clone()
+                    song: self.song.clone(),
+                    params: self.params.clone(),
                 },
                 [size: [Auto, 70.0]]
             ))
-            .push(node!(AllTracksDragSource {
-                song: self.song.clone()
-            })),
+            .push(
+                node!(
+                    widgets::Div::new(),
+                    [size_pct: [100, Auto], direction: Row]
+                )
+                .push(node!(
+                    AllTracksDragSource {
+                        song: self.song.clone()
+                    },
+                    [size_pct: [50, 100]]
+                ))
+                .push(node!(
+                    ClipDragSource {
+                        label: "CHAIN",
+                        param: self.params.clip_chain.clone(),
+                        song: self.song.clone(),
+                        file: |f| f.chain.as_ref(),
+                    },
+                    [size_pct: [25, 100]]
+                ))
+                .push(node!(
+                    ClipDragSource {
+                        label: "PHRASE",
+                        param: self.params.clip_phrase.clone(),
+                        song: self.song.clone(),
+                        file: |f| f.phrase.as_ref(),
+                    },
+                    [size_pct: [25, 100]]
+                )),
+            ),
         )
     }
 }
@@ -81,6 +113,7 @@ impl Component for AllTracksDragSource {
 #[derive(Debug)]
 pub struct TracksDragSource {
     song: Option<Arc<MidiTempFiles>>,
+    params: Arc<M8Params>,
 }
 
 impl Component for TracksDragSource {
@@ -94,29 +127,42 @@ impl Component for TracksDragSource {
                 axis_alignment: Stretch,
             ]
         );
-        for i in 0..8 {
-            let has_data = self
+        // Without a song, show the eight song tracks
+        let n_tracks = self.song.as_ref().map(|f| f.tracks.len()).unwrap_or(8);
+        // In instrument mode, the tracks are instruments, which can't be turned off
+        let song_tracks = !self.params.instrument_mode.value();
+        for i in 0..n_tracks {
+            let (label, has_data) = self
                 .song
                 .as_ref()
-                .map(|f| f.tracks[i].is_some())
-                .unwrap_or(false);
+                .map(|f| (f.tracks[i].0.clone(), f.tracks[i].1.is_some()))
+                .unwrap_or_else(|| (format!("{}", i + 1), false));
+            let mut column = node!(
+                widgets::Div::new(),
+                [
+                    size_pct: [Auto, 100],
+                    direction: Column,
+                    axis_alignment: Stretch,
+                    margin: [5],
+                ]
+            )
+            .push(node!(
+                widgets::Text::new(txt!(label))
+                    .style("h_alignment", HorizontalPosition::Left)
+                    .style("color", if has_data { LIGHT_GRAY } else { MID_GRAY }),
+                [margin: [3]]
+            ));
+            if song_tracks {
+                column = column.push(
+                    node!(TrackToggle {
+                        param: self.params.tracks.clone(),
+                        track: i + 1,
+                    })
+                    .key(i as u64),
+                );
+            }
             container = container.push(
-                node!(
-                    widgets::Div::new(),
-                    [
-                        size_pct: [Auto, 100],
-                        direction: Column,
-                        axis_alignment: Stretch,
-                        margin: [5],
-                    ]
-                )
-                .push(node!(
-                    widgets::Text::new(txt!(format!("{}", i + 1)))
-                        .style("h_alignment", HorizontalPosition::Left)
-                        .style("color", if has_data { LIGHT_GRAY } else { MID_GRAY }),
-                    [margin: [3]]
-                ))
-                .push(
+                column.push(
                     node!(
                         TrackDragSource {
                             song: self.song.clone(),
@@ -132,6 +178,39 @@ impl Component for TracksDragSource {
     }
 }
 
+/// Turns a song track on or off in ALL TRACKS
+#[derive(Debug)]
+pub struct TrackToggle {
+    param: Arc<IntParam>,
+    /// Song track (1-8)
+    track: usize,
+}
+
+impl Component for TrackToggle {
+    fn view(&self) -> Option<Node> {
+        let enabled = TrackSet::from_bits(self.param.value() as u8).contains(self.track);
+        Some(node!(
+            widgets::Text::new(txt!(if enabled { "ON" } else { "OFF" }))
+                .style("h_alignment", HorizontalPosition::Left)
+                .style("color", if enabled { BLUE } else { MID_GRAY }),
+            [margin: [3]]
+        ))
+    }
+
+    fn on_click(&mut self, event: &mut Event<event::Click>) {
+        let mut tracks = TrackSet::from_bits(self.param.value() as u8);
+        tracks.set(self.track, !tracks.contains(self.track));
+        let param = self.param.as_ptr();
+        event.emit(msg!(AppMsg::BeginSettingParam { param }));
+        event.emit(msg!(AppMsg::SetParam {
+            param,
+            norm_value: self.param.preview_normalized(tracks.bits() as i32),
+        }));
+        event.emit(msg!(AppMsg::EndSettingParam { param }));
+        event.stop_bubbling();
+    }
+}
+
 #[derive(Debug)]
 pub struct TrackDragSource {
     // Why do we pass in the whole song? If we clone a temp file,
@@ -145,7 +224,7 @@ impl Component for TrackDragSource {
         let has_data = self
             .song
             .as_ref()
-            .map(|f| f.tracks[self.track].is_some())
+            .map(|f| f.tracks[self.track].1.is_some())
             .unwrap_or(false);
 
         Some(node!(
@@ -158,7 +237,7 @@ impl Component for TrackDragSource {
         if let Some(f) = self
             .song
             .as_ref()
-            .and_then(|s| s.tracks[self.track].as_ref())
+            .and_then(|s| s.tracks[self.track].1.as_ref())
         {
             current_window()
                 .unwrap()
@@ -167,3 +246,51 @@ impl Component for TrackDragSource {
         }
     }
 }
+
+/// A single chain or phrase, picked with its parameter
+#[derive(Debug)]
+pub struct ClipDragSource {
+    label: &'static str,
+    param: Arc<IntParam>,
+    song: Option<Arc<MidiTempFiles>>,
+    file: fn(&MidiTempFiles) -> Option<&TempFile>,
+}
+
+impl Component for ClipDragSource {
+    fn view(&self) -> Option<Node> {
+        let has_data = self
+            .song
+            .as_ref()
+            .map(|f| (self.file)(f).is_some())
+            .unwrap_or(false);
+        Some(
+            node!(
+                widgets::Div::new().bg(if has_data { BLUE } else { MID_GRAY }),
+                [
+                    size_pct: [100],
+                    margin: [10, 5],
+                    padding: [5],
+                    direction: Column,
+                    cross_alignment: Center,
+                    axis_alignment: Center
+                ],
+            )
+            .push(node!(widgets::Text::new(txt!(self.label))
+                .style("h_alignment", HorizontalPosition::Center)
+                .style(
+                    "color",
+                    if has_data { DARK_GRAY } else { LIGHT_GRAY }
+                )))
+            .push(node!(BasicParam::new(self.param.clone()))),
+        )
+    }
+
+    fn on_drag_start(&mut self, event: &mut Event<event::DragStart>) {
+        if let Some(f) = self.song.as_ref().and_then(|s| (self.file)(s)) {
+            current_window()
+                .unwrap()
+                .start_drag(Data::Filepath(f.path().into()));
+            event.stop_bubbling();
+        }
+    }
+}
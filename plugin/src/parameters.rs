@@ -16,21 +16,6 @@ impl Parameters {
 
 impl lemna::Component for Parameters {
     fn view(&self) -> Option<Node> {
-        let labels = node!(
-            widgets::Div::new(),
-            [size_pct: [Auto, 100], direction: Column]
-        )
-        .push(node!(widgets::Text::new(txt!("START"))))
-        .push(node!(widgets::Text::new(txt!("MAX LEN"))))
-        .push(node!(widgets::Text::new(txt!("TRANSPOSE"))));
-        let params = node!(
-            widgets::Div::new(),
-            [size_pct: [Auto, 100], direction: Column]
-        )
-        .push(node!(BasicParam::new(self.params.start.clone())))
-        .push(node!(BasicParam::new(self.params.max_len.clone())))
-        .push(node!(BasicParam::new(self.params.transpose.clone())));
-
         Some(
             node!(
                 widgets::Div::new(),
@@ -38,8 +23,87 @@ impl lemna::Component for Parameters {
                  padding: [25, 15],
                  direction: Row]
             )
-            .push(labels)
-            .push(params),
+            .push(param_group(vec![
+                ("START", node!(BasicParam::new(self.params.start.clone()))),
+                ("END", node!(BasicParam::new(self.params.end.clone()))),
+                (
+                    "REPEAT",
+                    node!(BasicParam::new(self.params.repetitions.clone())),
+                ),
+                (
+                    "LOOP",
+                    node!(BasicParam::new(self.params.loop_until_longest.clone())),
+                ),
+                (
+                    "MAX LEN",
+                    node!(BasicParam::new(self.params.max_len.clone())),
+                ),
+                (
+                    "TRANSPOSE",
+                    node!(BasicParam::new(self.params.transpose.clone())),
+                ),
+            ]))
+            .push(param_group(vec![
+                (
+                    "CHANNEL",
+                    node!(BasicParam::new(self.params.channel.clone())),
+                ),
+                (
+                    "MODE",
+                    node!(BasicParam::new(self.params.instrument_mode.clone())),
+                ),
+                ("CC", node!(BasicParam::new(self.params.fx_map.clone()))),
+                (
+                    "RANGE",
+                    node!(BasicParam::new(self.params.out_of_range_notes.clone())),
+                ),
+                ("ARP", node!(BasicParam::new(self.params.arpeggios.clone()))),
+            ]))
+            .push(param_group(vec![
+                (
+                    "PROG",
+                    node!(BasicParam::new(self.params.program_changes.clone())),
+                ),
+                (
+                    "TIME",
+                    node!(BasicParam::new(self.params.phrase_time_signature.clone())),
+                ),
+                (
+                    "VELOCITY",
+                    node!(BasicParam::new(self.params.velocity_curve.clone())),
+                ),
+                ("PPQ", node!(BasicParam::new(self.params.ppq.clone()))),
+                (
+                    "FORMAT",
+                    node!(BasicParam::new(self.params.single_track.clone())),
+                ),
+            ]))
+            .push(param_group(
+                self.params
+                    .fx_mappings()
+                    .into_iter()
+                    .map(|(command, param)| (command, node!(BasicParam::new(param.clone()))))
+                    .collect(),
+            )),
         )
     }
 }
+
+/// A column of labels next to a column of their parameters
+fn param_group(params: Vec<(&str, Node)>) -> Node {
+    let mut labels = node!(
+        widgets::Div::new(),
+        [size_pct: [Auto, 100], direction: Column]
+    );
+    let mut values = node!(
+        widgets::Div::new(),
+        [size_pct: [Auto, 100], direction: Column, margin: [0, 15]]
+    );
+    for (label, param) in params {
+        labels = labels.push(node!(widgets::Text::new(txt!(label))));
+        values = values.push(param);
+    }
+    node!(widgets::Div::new(), [size_pct: [Auto, 100], direction: Row])
+        .push(labels)
+        .push(values)
+}
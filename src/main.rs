@@ -1,12 +1,17 @@
 use std::error::Error;
 use std::fs::File;
-use std::io::Write;
+use std::io::{BufWriter, Write};
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
 use m8_files::Song;
+use midi_msg::Channel;
 
+use midi_m8_core::fx_map::FxMap;
+use midi_m8_core::midi_file::WriteOptions;
+use midi_m8_core::row_range::RowRange;
 use midi_m8_core::song_to_midi::*;
+use midi_m8_core::track_set::TrackSet;
 
 #[derive(Parser)]
 #[command(version, about)]
@@ -15,6 +20,9 @@ struct Args {
     #[arg()]
     input_file: String,
 
+    #[command(subcommand)]
+    clip: Option<Clip>,
+
     /// Output file name
     #[arg(short='o', long, default_value = Args::DEFAULT_OUTPUT_NAME)]
     output: String,
@@ -22,15 +30,43 @@ struct Args {
     /// How to map M8 note numbers to Midi Note numbers
     #[arg(short, long, default_value_t = 36)]
     global_transpose: i16,
+    /// What to do with notes transposed outside of the Midi note range: clamp, fold (by octaves) or drop (with a warning)
+    #[arg(long)]
+    out_of_range: Option<String>,
+    /// How ARP commands are output: off (only the played note), chord (all of its notes at once), or the number of M8 ticks between the notes of an arpeggio
+    #[arg(long)]
+    arp: Option<String>,
 
     /// Only output track number (1-8)
     #[arg(long, short = 't', id = "ONLY_TRACK_N")]
     only_track: Option<usize>,
+    /// Only output the given tracks, as a comma separated list, e.g. 1,3,6
+    #[arg(long, conflicts_with = "ONLY_TRACK_N")]
+    tracks: Option<String>,
+
+    /// Output one track per instrument, rather than one per song track
+    #[arg(long, short = 'i')]
+    instrument_mode: bool,
 
-    /// Start from this song position (hex: 00-FF)
+    /// Start from this song position. Hex as on the M8 (00-FF, so 10 is position 16), or decimal with a 0d prefix (0d0-0d255)
     #[arg(long, short = 's')]
     start_from: Option<String>,
 
+    /// Stop before this song position. Hex as on the M8 (01-FF), or decimal with a 0d prefix (0d1-0d255)
+    #[arg(long, short = 'e')]
+    end_at: Option<String>,
+
+    /// Song positions to play (hex as on the M8), e.g. 10..20 to start from 10 and stop before 20, or 10..=FF to play 10 to FF. Either end can be left out
+    #[arg(long, conflicts_with_all = ["start_from", "end_at"])]
+    rows: Option<String>,
+
+    /// Play the song this many times. Tracks loop back to the start of their block of rows when they reach an empty row, like on the M8
+    #[arg(long, short = 'r', default_value_t = 1)]
+    repeat: u32,
+    /// Loop tracks that are shorter than the longest track until it ends
+    #[arg(long)]
+    loop_until_longest: bool,
+
     /// Cap the maximum note length to this value in quarter notes
     #[arg(short, long)]
     max_note_length: Option<f32>,
@@ -58,11 +94,100 @@ struct Args {
     /// Cap the maximum note length for track 8 to this value in quarter notes
     #[arg(long, id = "TRACK_8_MAX_NOTE_LEN")]
     track_8_max_note_length: Option<f32>,
+
+    /// Midi channel to output on (1-16)
+    #[arg(short, long)]
+    channel: Option<u8>,
+    /// Midi channel for track 1 (1-16)
+    #[arg(long, id = "TRACK_1_CHANNEL")]
+    track_1_channel: Option<u8>,
+    /// Midi channel for track 2 (1-16)
+    #[arg(long, id = "TRACK_2_CHANNEL")]
+    track_2_channel: Option<u8>,
+    /// Midi channel for track 3 (1-16)
+    #[arg(long, id = "TRACK_3_CHANNEL")]
+    track_3_channel: Option<u8>,
+    /// Midi channel for track 4 (1-16)
+    #[arg(long, id = "TRACK_4_CHANNEL")]
+    track_4_channel: Option<u8>,
+    /// Midi channel for track 5 (1-16)
+    #[arg(long, id = "TRACK_5_CHANNEL")]
+    track_5_channel: Option<u8>,
+    /// Midi channel for track 6 (1-16)
+    #[arg(long, id = "TRACK_6_CHANNEL")]
+    track_6_channel: Option<u8>,
+    /// Midi channel for track 7 (1-16)
+    #[arg(long, id = "TRACK_7_CHANNEL")]
+    track_7_channel: Option<u8>,
+    /// Midi channel for track 8 (1-16)
+    #[arg(long, id = "TRACK_8_CHANNEL")]
+    track_8_channel: Option<u8>,
+    /// Use the channel of each note's instrument (MIDI OUT channel, or the instrument number)
+    #[arg(long)]
+    instrument_channels: bool,
+
+    /// Override how M8 FX commands are output, e.g. VOL=cc7,PIT=pb,CUT=off (cc<0-119>, pb: pitch bend, at: aftertouch, off)
+    #[arg(long)]
+    fx_map: Option<String>,
+    /// Don't output any CC, pitch bend or aftertouch for FX commands
+    #[arg(long)]
+    no_fx_map: bool,
+
+    /// Output a program change when the instrument changes (MIDI OUT program, or the instrument number)
+    #[arg(short, long)]
+    program_changes: bool,
+    /// Output a bank select before program changes, for MIDI OUT instruments with a bank set
+    #[arg(long, requires = "program_changes")]
+    bank_select: bool,
+
+    /// Time signature, e.g. 4/4 or 7/8. `phrase` makes each phrase one bar long, based on the first groove
+    #[arg(long)]
+    time_signature: Option<String>,
+
+    /// How M8 velocities are turned into Midi velocities: linear, soft, hard or a fixed velocity (1-127)
+    #[arg(long)]
+    velocity_curve: Option<String>,
+
+    /// Print a report of the notes output by each track, and of anything that couldn't be converted
+    #[arg(long)]
+    report: bool,
+    /// Print the report as JSON, instead of the usual output
+    #[arg(long, conflicts_with = "report")]
+    report_json: bool,
+
+    /// Resolution of the Midi file in ticks per quarter note, e.g. 96, 480 or 960 (default 24)
+    #[arg(long)]
+    ppq: Option<u16>,
+
+    /// Midi file format: 1 writes one track per song track, 0 merges them into a single track.
+    /// Unless channels are set, format 0 puts each track on its own channel
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(0..=1))]
+    format: u16,
+    /// Write a smaller file for devices with little memory, using running status and note ons
+    /// with a velocity of 0 as note offs
+    #[arg(long)]
+    compact: bool,
 }
 impl Args {
     const DEFAULT_OUTPUT_NAME: &str = "tracks.midi";
 }
 
+/// Output a single chain or phrase instead of the song, using the settings of track 1,
+/// or of the first track given with --only-track or --tracks
+#[derive(Subcommand)]
+enum Clip {
+    /// Output a single chain
+    Chain {
+        /// Chain number (hex: 00-FE)
+        chain: String,
+    },
+    /// Output a single phrase
+    Phrase {
+        /// Phrase number (hex: 00-FE)
+        phrase: String,
+    },
+}
+
 fn main() {
     human_panic::setup_panic!();
     match run() {
@@ -83,7 +208,7 @@ fn run() -> Result<(), Box<dyn Error>> {
 
     if let Some(track) = args.only_track {
         if track > 0 && track < 9 {
-            config.tracks = track..(track + 1);
+            config.tracks = TrackSet::from_tracks([track])?;
             if out_name == Args::DEFAULT_OUTPUT_NAME {
                 out_name = format!("track-{}.midi", track);
             }
@@ -95,17 +220,40 @@ fn run() -> Result<(), Box<dyn Error>> {
         }
     }
 
-    if let Some(start_from) = &args.start_from {
-        if let Ok(start_from) = i8::from_str_radix(start_from, 16) {
-            config.start_from = start_from as u8;
+    if let Some(tracks) = &args.tracks {
+        config.tracks = tracks.parse()?;
+    }
+
+    if let Some(rows) = &args.rows {
+        config.rows = rows.parse()?;
+    } else {
+        let start = args
+            .start_from
+            .as_deref()
+            .map(RowRange::parse_row)
+            .transpose()?;
+        let end = args
+            .end_at
+            .as_deref()
+            .map(RowRange::parse_row)
+            .transpose()?;
+        config.rows = RowRange::new(start.unwrap_or(0), end)?;
+    }
+
+    if let Some(ppq) = args.ppq {
+        if (1..=0x7FFF).contains(&ppq) {
+            config.ppq = ppq;
         } else {
             println!(
-                "Warning: START_FROM must be a hex number from 00-FF. Got {}. Defaulting to starting from step 0.",
-                start_from
+                "Warning: PPQ must be from 1-32767. Got {}. Defaulting to {}.",
+                ppq, TICKS_PER_QUARTER_NOTE
             );
         }
     }
 
+    config.repetitions = args.repeat.max(1);
+    config.loop_until_longest = args.loop_until_longest;
+
     let max_note_lengths: [Option<f32>; 8] = [
         args.track_1_max_note_length,
         args.track_2_max_note_length,
@@ -119,18 +267,133 @@ fn run() -> Result<(), Box<dyn Error>> {
 
     for (i, len) in max_note_lengths.iter().enumerate() {
         if let Some(l) = len.or(args.max_note_length) {
-            config.max_note_length[i] = (l * TICKS_PER_QUARTER_NOTE as f32) as u32;
+            config.max_note_length[i] = (l * config.ppq as f32) as u32;
+        }
+    }
+
+    let channels: [Option<u8>; 8] = [
+        args.track_1_channel,
+        args.track_2_channel,
+        args.track_3_channel,
+        args.track_4_channel,
+        args.track_5_channel,
+        args.track_6_channel,
+        args.track_7_channel,
+        args.track_8_channel,
+    ];
+
+    for (i, channel) in channels.iter().enumerate() {
+        if let Some(c) = channel.or(args.channel) {
+            if (1..=16).contains(&c) {
+                config.channels[i] = Channel::from_u8(c - 1);
+            } else {
+                println!(
+                    "Warning: channels must be from 1-16. Got {} for track {}. Defaulting to channel 1.",
+                    c,
+                    i + 1
+                );
+            }
         }
     }
+    config.instrument_channels = args.instrument_channels;
+    if args.instrument_mode {
+        config.track_mode = TrackMode::Instruments;
+    }
+    // Merged tracks are kept apart by their channels
+    let channels_set =
+        args.channel.is_some() || channels.iter().any(Option::is_some) || args.instrument_channels;
+    if args.format == 0 && !channels_set {
+        config = config.separate_channels();
+    }
+
+    if args.no_fx_map {
+        config.fx_map = FxMap::empty();
+    }
+    if let Some(overrides) = &args.fx_map {
+        config.fx_map.apply_overrides(overrides)?;
+    }
+    config.program_changes = args.program_changes;
+    config.bank_select = args.bank_select;
+    if let Some(time_signature) = &args.time_signature {
+        config.time_signature = time_signature.parse()?;
+    }
+    if let Some(velocity_curve) = &args.velocity_curve {
+        config.velocity_curve = velocity_curve.parse()?;
+    }
+    if let Some(out_of_range) = &args.out_of_range {
+        config.out_of_range_notes = out_of_range.parse()?;
+    }
+    if let Some(arp) = &args.arp {
+        config.arpeggios = arp.parse()?;
+    }
 
     // Load m8s file
     let mut f = File::open(args.input_file)?;
     let song = Song::read(&mut f)?;
     // dbg!(song);
 
-    // Write midi file
-    let mut f_out = File::create(out_name.clone())?;
-    f_out.write_all(&song_to_midi(&song, &config))?;
-    println!("Wrote {}", &out_name);
+    let midi_file = match &args.clip {
+        Some(Clip::Chain { chain }) => {
+            let chain = u8::from_str_radix(chain, 16)
+                .ok()
+                .and_then(|c| chain_to_midi_file(&song, c, &config).map(|f| (c, f)));
+            let (chain, midi_file) =
+                chain.ok_or("CHAIN must be a hex number of a chain in the song")?;
+            if args.output == Args::DEFAULT_OUTPUT_NAME {
+                out_name = format!("chain-{:02X}.midi", chain);
+            }
+            midi_file
+        }
+        Some(Clip::Phrase { phrase }) => {
+            let phrase = u8::from_str_radix(phrase, 16)
+                .ok()
+                .and_then(|p| phrase_to_midi_file(&song, p, &config).map(|f| (p, f)));
+            let (phrase, midi_file) =
+                phrase.ok_or("PHRASE must be a hex number of a phrase in the song")?;
+            if args.output == Args::DEFAULT_OUTPUT_NAME {
+                out_name = format!("phrase-{:02X}.midi", phrase);
+            }
+            midi_file
+        }
+        None => {
+            let (midi_file, report) = song_to_midi_file_with_report(&song, &config);
+            if args.report_json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else if args.report {
+                print!("{}", report);
+            } else {
+                // Dropped notes are always worth knowing about
+                for track in report.tracks.iter() {
+                    for note in track
+                        .out_of_range_notes
+                        .iter()
+                        .filter(|n| n.output.is_none())
+                    {
+                        println!("Warning: track {}, {}", track.track, note);
+                    }
+                }
+            }
+            midi_file
+        }
+    };
+
+    let midi_file = if args.format == 0 {
+        midi_file.to_single_track()
+    } else {
+        midi_file
+    };
+    // Write midi file, without leaving an empty file behind if it is invalid
+    midi_file.validate()?;
+    let mut f_out = BufWriter::new(File::create(out_name.clone())?);
+    let options = if args.compact {
+        WriteOptions::compact()
+    } else {
+        WriteOptions::default()
+    };
+    midi_file.write_midi(&mut f_out, &options)?;
+    f_out.flush()?;
+    if !args.report_json {
+        println!("Wrote {}", &out_name);
+    }
     Ok(())
 }